@@ -1,48 +1,94 @@
-use crate::{arsync, commands, config};
+use crate::{arsync, commands, config, logging, secrets};
 use clap::{arg, Command};
 
 pub fn command() -> Command {
     Command::new("arsync")
         .args(args())
+        .args(logging::args())
+        .args(secrets::args())
         .subcommand(crate::commands::config::command())
+        .subcommand(crate::commands::login::command())
         .subcommand(crate::commands::drives::command())
         .subcommand(crate::commands::down::command())
         .subcommand(crate::commands::up::command())
+        .subcommand(crate::commands::versions::command())
+        .subcommand(crate::commands::restore::command())
+        .subcommand(crate::commands::prune::command())
+        .subcommand(crate::commands::daemon::command())
+        .subcommand(crate::commands::run::command())
+        .subcommand(crate::commands::status::command())
 }
 
 fn args() -> Vec<clap::Arg> {
-    vec![arg!(-c --config <CONFIG_FILE_PATH> "Path to the config file path, like `config.toml`")]
+    vec![
+        arg!(-c --config <CONFIG_FILE_PATH> "配置文件, 默认使用环境变量 `ARSYNC_CONFIG` 或者 `~/.config/arsync/config.toml`")
+            .required(false),
+        arg!(--account <ACCOUNT> "使用配置文件 `accounts` 中的账户, 默认使用顶层的 `app` 和 `access_token`")
+            .required(false)
+            .global(true),
+    ]
 }
 
 pub async fn run_command() -> anyhow::Result<()> {
     let matches = arsync::command().get_matches();
-    let config_path: Option<&String> = matches.get_one("config");
+    logging::init(&matches)?;
+    secrets::init(&matches)?;
     if let Some((command_name, args)) = matches.subcommand() {
-        if let Some(config_path) = config_path {
-            config::set_path(config_path.as_str()).await?;
-            match command_name {
-                commands::config::COMMAND_NAME => {
-                    commands::config::run_sub_command(args).await?;
-                }
-                commands::drives::COMMAND_NAME => {
-                    commands::drives::run_sub_command(args).await?;
-                }
-                commands::down::COMMAND_NAME => {
-                    commands::down::run_sub_command(args).await?;
-                }
-                commands::up::COMMAND_NAME => {
-                    commands::up::run_sub_command(args).await?;
-                }
-                _ => {
-                    arsync::command().print_help()?;
-                }
-            }
-        } else {
-            arsync::command().print_help()?;
-            eprintln!("\nError: config file path is required when using subcommands\n\n");
-        }
+        let config_path = match matches.get_one::<String>("config") {
+            Some(config_path) => config_path.clone(),
+            None => config::default_path()?,
+        };
+        // 只有配置和登录时新建配置文件
+        let create = command_name == commands::config::COMMAND_NAME
+            || command_name == commands::login::COMMAND_NAME;
+        config::set_path(config_path.as_str(), create).await?;
+        let account = config::selected_account(args);
+        config::with_account(account, run_sub_command(command_name, args)).await?;
     } else {
         arsync::command().print_help()?;
     }
     Ok(())
 }
+
+/// 运行子命令, 期间请求的授权失效时提示 `--account` 选择的账户重新登录
+async fn run_sub_command(command_name: &str, args: &clap::ArgMatches) -> anyhow::Result<()> {
+    match command_name {
+        commands::config::COMMAND_NAME => {
+            commands::config::run_sub_command(args).await?;
+        }
+        commands::login::COMMAND_NAME => {
+            commands::login::run_sub_command(args).await?;
+        }
+        commands::drives::COMMAND_NAME => {
+            commands::drives::run_sub_command(args).await?;
+        }
+        commands::down::COMMAND_NAME => {
+            commands::down::run_sub_command(args).await?;
+        }
+        commands::up::COMMAND_NAME => {
+            commands::up::run_sub_command(args).await?;
+        }
+        commands::versions::COMMAND_NAME => {
+            commands::versions::run_sub_command(args).await?;
+        }
+        commands::restore::COMMAND_NAME => {
+            commands::restore::run_sub_command(args).await?;
+        }
+        commands::prune::COMMAND_NAME => {
+            commands::prune::run_sub_command(args).await?;
+        }
+        commands::daemon::COMMAND_NAME => {
+            commands::daemon::run_sub_command(args).await?;
+        }
+        commands::run::COMMAND_NAME => {
+            commands::run::run_sub_command(args).await?;
+        }
+        commands::status::COMMAND_NAME => {
+            commands::status::run_sub_command(args).await?;
+        }
+        _ => {
+            arsync::command().print_help()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use anyhow::Context;
+use chrono::{Local, NaiveTime};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// 带宽限制, 例如 `1M`, 或者按时间段 `08:00,512K 18:00,10M 23:00,off`
+///
+/// 每一段从指定的时间开始生效, 直到下一段开始, 第一段之前使用最后一段(跨越午夜)
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    /// (开始时间, 每秒字节数), None 为不限速
+    entries: Vec<(NaiveTime, Option<u64>)>,
+}
+
+impl BandwidthSchedule {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if !value.contains(',') {
+            return Ok(BandwidthSchedule {
+                entries: vec![(NaiveTime::MIN, parse_rate(value)?)],
+            });
+        }
+        let mut entries = vec![];
+        for item in value.split_whitespace() {
+            entries.push(parse_entry(item).with_context(|| format!("时间段格式不正确: {}", item))?);
+        }
+        entries.sort_by_key(|(time, _)| *time);
+        Ok(BandwidthSchedule { entries })
+    }
+
+    /// 当前时间的限速
+    fn rate(&self) -> Option<u64> {
+        self.rate_at(Local::now().time())
+    }
+
+    fn rate_at(&self, now: NaiveTime) -> Option<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= now)
+            .or(self.entries.last())
+            .and_then(|(_, rate)| *rate)
+    }
+}
+
+/// `08:00,512K`
+fn parse_entry(item: &str) -> anyhow::Result<(NaiveTime, Option<u64>)> {
+    let (time, rate) = item
+        .split_once(',')
+        .with_context(|| "需要 `时间,带宽`, 例如 `08:00,512K`")?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("时间格式不正确: {}", time))?;
+    Ok((time, parse_rate(rate)?))
+}
+
+/// `512K`, `10M`, `1.5G`, 单位为每秒字节数(1024进制), `off` 或 `0` 为不限速
+fn parse_rate(value: &str) -> anyhow::Result<Option<u64>> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let upper = value.to_ascii_uppercase();
+    let upper = upper.trim_end_matches("/S").trim_end_matches('B');
+    let (number, unit) = match upper.chars().last() {
+        Some('K') => (&upper[..upper.len() - 1], 1u64 << 10),
+        Some('M') => (&upper[..upper.len() - 1], 1 << 20),
+        Some('G') => (&upper[..upper.len() - 1], 1 << 30),
+        _ => (upper, 1),
+    };
+    let number = number
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite() && *number >= 0.0)
+        .with_context(|| format!("带宽格式不正确: {}, 例如 `512K`, `10M` 或者 `off`", value))?;
+    let rate = (number * unit as f64) as u64;
+    Ok(if rate == 0 { None } else { Some(rate) })
+}
+
+/// 令牌桶, 一次 `up` 或 `down` 的所有传输共享, 最多积攒1秒的流量
+///
+/// 每次运行创建自己的限速, 守护进程中同时运行的任务互不影响
+pub struct Limiter {
+    schedule: BandwidthSchedule,
+    /// (可用字节数, 上次更新时间), 可用字节数为负时需要等待
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Limiter {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        Limiter {
+            schedule,
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// 传输 bytes 字节时调用, 超过限速时等待
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.schedule.rate() else {
+            return;
+        };
+        let rate = rate as f64;
+        // 等待期间持有锁, 其他传输排队等待
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.1).as_secs_f64();
+        bucket.0 = (bucket.0 + elapsed * rate).min(rate) - bytes as f64;
+        bucket.1 = now;
+        if bucket.0 < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-bucket.0 / rate)).await;
+        }
+    }
+}
+
+/// 有限速时等待
+pub async fn acquire(limiter: Option<&Limiter>, bytes: usize) {
+    if let Some(limiter) = limiter {
+        limiter.acquire(bytes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(parse_rate("512K").unwrap(), Some(512 << 10));
+        assert_eq!(parse_rate("1.5M").unwrap(), Some(3 << 19));
+        assert_eq!(parse_rate("10MB/s").unwrap(), Some(10 << 20));
+        assert_eq!(parse_rate("2048").unwrap(), Some(2048));
+        assert_eq!(parse_rate("off").unwrap(), None);
+        assert_eq!(parse_rate("0").unwrap(), None);
+        assert!(parse_rate("abc").is_err());
+        assert!(parse_rate("-1M").is_err());
+    }
+
+    #[test]
+    fn schedule_wraps_past_midnight() {
+        let schedule = BandwidthSchedule::parse("08:00,512K 18:00,10M 23:00,off").unwrap();
+        assert_eq!(schedule.rate_at(time(9, 0)), Some(512 << 10));
+        assert_eq!(schedule.rate_at(time(18, 0)), Some(10 << 20));
+        assert_eq!(schedule.rate_at(time(23, 30)), None);
+        // 第一段之前使用最后一段, 与书写的顺序无关
+        let schedule = BandwidthSchedule::parse("23:00,1M 07:00,off").unwrap();
+        assert_eq!(schedule.rate_at(time(3, 0)), Some(1 << 20));
+        assert_eq!(schedule.rate_at(time(12, 0)), None);
+    }
+
+    #[test]
+    fn schedule_errors_name_the_entry() {
+        let err = BandwidthSchedule::parse("08:00,1M 25:00,2M").unwrap_err();
+        assert!(format!("{:#}", err).contains("25:00,2M"));
+        assert!(BandwidthSchedule::parse("08:00 1M").is_err());
+        assert_eq!(
+            BandwidthSchedule::parse("1M").unwrap().rate_at(time(12, 0)),
+            Some(1 << 20)
+        );
+    }
+}
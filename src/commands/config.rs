@@ -1,41 +1,297 @@
-use crate::config;
-use alipan::GrantType;
+use crate::config::{self, JobDirection};
+use crate::jobs::validate_jobs;
+use crate::oauth::{access_token_by_code, random_state, Pkce, DEFAULT_SCOPE};
+use crate::secrets;
+use alipan::oauth_access_token::OauthAccessToken;
+use alipan::AccessToken;
+use anyhow::Context;
 use clap::{arg, Command};
+use once_cell::sync::OnceCell;
 use serde_json::json;
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::Notify;
 use warp::http::Response;
 use warp::hyper::Body;
 use warp::{Filter, Reply};
 
 pub const COMMAND_NAME: &str = "config";
 
+struct ServerConfig {
+    /// `--account` 选择的账户, 网页中配置的app和token保存到这个账户
+    account: Option<String>,
+    redirect_uri: String,
+    scope: String,
+}
+
+/// 正在进行的授权, 回调时校验 state, 并使用其中的 code_verifier 换取token
+struct PendingAuthorize {
+    state: String,
+    pkce: Pkce,
+}
+
+static SERVER_CELL: OnceCell<ServerConfig> = OnceCell::new();
+static PENDING_AUTHORIZE: Mutex<Option<PendingAuthorize>> = Mutex::new(None);
+/// 授权成功后停止服务
+static AUTHORIZED: Notify = Notify::const_new();
+
+fn server_config() -> anyhow::Result<&'static ServerConfig> {
+    SERVER_CELL.get().with_context(|| "server config not set")
+}
+
+fn account() -> Option<&'static str> {
+    SERVER_CELL
+        .get()
+        .and_then(|server| server.account.as_deref())
+}
+
 pub fn command() -> Command {
-    Command::new(COMMAND_NAME).args(args())
+    Command::new(COMMAND_NAME)
+        .args(args())
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("set-app")
+                .about("设置app, 不设置 client_secret 时使用PKCE")
+                .args([
+                    arg!(--"client-id" <CLIENT_ID> "app的 client_id"),
+                    arg!(--"client-secret" <CLIENT_SECRET> "app的 client_secret")
+                        .required(false)
+                        .conflicts_with("client-secret-file"),
+                    arg!(--"client-secret-file" <FILE> "从文件读取 client_secret, 去掉首尾空白")
+                        .required(false),
+                ]),
+        )
+        .subcommand(Command::new("show").about("显示配置, 隐藏密钥、token和密码"))
+        .subcommand(
+            Command::new("import-token")
+                .about("从json文件导入token, `-` 为标准输入")
+                .arg(
+                    arg!(<FILE> "`export-token` 导出的token, 或者 `/oauth/access_token` 的返回值"),
+                ),
+        )
+        .subcommand(Command::new("export-token").about("以json格式输出token"))
+        .subcommand(Command::new("validate").about("检查配置文件, 有问题时返回错误"))
+        .subcommand(
+            Command::new("encrypt")
+                .about("使用主密码或者密钥文件加密配置文件中的密钥、token和密码"),
+        )
+        .subcommand(Command::new("decrypt").about("取消配置文件的加密, 以明文保存"))
 }
 
 fn args() -> Vec<clap::Arg> {
-    vec![arg!(--port <PASSWORD> "端口 默认是58080").required(false)]
+    vec![
+        arg!(--port <PORT> "端口 默认是58080").required(false),
+        arg!(--bind <ADDRESS> "监听的地址 默认是127.0.0.1").required(false),
+        arg!(--"redirect-uri" <URI> "授权后回调的地址, 默认根据监听的地址和端口生成")
+            .required(false),
+        arg!(--scope <SCOPE> "申请的授权范围, 多个用逗号分隔")
+            .required(false)
+            .default_value(DEFAULT_SCOPE),
+    ]
 }
 
 pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    match args.subcommand() {
+        Some(("set-app", args)) => return set_app(args).await,
+        Some(("show", _)) => return show().await,
+        Some(("import-token", args)) => return import_token(args).await,
+        Some(("export-token", args)) => return export_token(args).await,
+        Some(("validate", _)) => return validate().await,
+        Some(("encrypt", _)) => return encrypt().await,
+        Some(("decrypt", _)) => return decrypt().await,
+        _ => {}
+    }
     // run warp server
     let port = args
         .get_one::<String>("port")
         .map_or("58080", |v| v.as_str())
         .parse::<u16>()?;
-    run_warp_server(port).await?;
+    let bind = args
+        .get_one::<String>("bind")
+        .map_or("127.0.0.1", |v| v.as_str())
+        .parse::<IpAddr>()
+        .with_context(|| "监听的地址不正确")?;
+    // 回环地址使用 localhost, 与开发者中心常用的配置一致
+    let host = if bind.is_loopback() || bind.is_unspecified() {
+        "localhost".to_string()
+    } else {
+        match bind {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        }
+    };
+    let redirect_uri = match args.get_one::<String>("redirect-uri") {
+        Some(redirect_uri) => redirect_uri.clone(),
+        None => format!("http://{}:{}/oauth_authorize", host, port),
+    };
+    let scope: &String = args.get_one("scope").with_context(|| "scope is required")?;
+    let _ = SERVER_CELL.set(ServerConfig {
+        account: config::selected_account(args).map(str::to_string),
+        redirect_uri,
+        scope: scope.clone(),
+    });
+    run_warp_server(bind, &host, port).await?;
+    Ok(())
+}
+
+async fn set_app(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let client_id: &String = args
+        .get_one("client-id")
+        .with_context(|| "client-id is required")?;
+    let client_secret = if let Some(secret) = args.get_one::<String>("client-secret") {
+        secret.clone()
+    } else if let Some(path) = args.get_one::<String>("client-secret-file") {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("读取文件失败 : {}", path))?
+            .trim()
+            .to_string()
+    } else {
+        String::new()
+    };
+    config::set_app_config(
+        config::selected_account(args),
+        config::AppConfig {
+            client_id: client_id.clone(),
+            client_secret,
+        },
+    )
+    .await
+}
+
+async fn show() -> anyhow::Result<()> {
+    let config = config::get_config().await?;
+    print!("{}", toml::to_string(&config.redacted())?);
+    Ok(())
+}
+
+async fn import_token(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let file: &String = args.get_one("FILE").with_context(|| "file is required")?;
+    let data = if file == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(file).with_context(|| format!("读取文件失败 : {}", file))?
+    };
+    let access_token = match serde_json::from_str::<AccessToken>(&data) {
+        Ok(access_token) => access_token,
+        Err(_) => AccessToken::wrap_oauth_token(
+            serde_json::from_str::<OauthAccessToken>(&data)
+                .with_context(|| format!("token格式不正确 : {}", file))?,
+        ),
+    };
+    if access_token.refresh_token.is_empty() {
+        return Err(anyhow::anyhow!("token中没有 refresh_token"));
+    }
+    config::set_access_token(config::selected_account(args), access_token).await
+}
+
+async fn export_token(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let access_token = config::get_access_token(config::selected_account(args)).await?;
+    println!("{}", serde_json::to_string_pretty(&access_token)?);
+    Ok(())
+}
+
+async fn encrypt() -> anyhow::Result<()> {
+    if config::get_config().await?.encryption.is_some() {
+        return Err(anyhow::anyhow!(
+            "配置文件已经加密, 更换主密码请先使用 `config decrypt` 取消加密"
+        ));
+    }
+    config::set_encryption(Some(secrets::new_encryption()?)).await?;
+    println!("配置文件已加密");
+    Ok(())
+}
+
+async fn decrypt() -> anyhow::Result<()> {
+    if config::get_config().await?.encryption.is_none() {
+        return Err(anyhow::anyhow!("配置文件没有加密"));
+    }
+    config::set_encryption(None).await?;
+    println!("配置文件已取消加密");
     Ok(())
 }
 
-async fn run_warp_server(port: u16) -> anyhow::Result<()> {
+async fn validate() -> anyhow::Result<()> {
+    let config = config::get_config().await?;
+    let mut problems = vec![];
+    // 默认账户只有在没有其他账户或者被任务使用时才需要配置
+    let default_used =
+        config.accounts.is_empty() || config.jobs.iter().any(|j| j.account.is_none());
+    for (name, app, token) in config.all_accounts() {
+        if name.is_none() && !default_used {
+            continue;
+        }
+        let name = name.map_or("默认账户".to_string(), |n| format!("账户 {}", n));
+        if app.client_id.is_empty() {
+            problems.push(format!("{} 没有配置 client_id", name));
+        }
+        if token.refresh_token.is_empty() {
+            problems.push(format!("{} 没有登录", name));
+        }
+    }
+    if let Err(err) = validate_jobs(&config.jobs) {
+        problems.push(format!("{:#}", err));
+    }
+    for job in &config.jobs {
+        if let Some(account) = &job.account {
+            if !config.accounts.contains_key(account) {
+                problems.push(format!("任务 {} 的账户不存在 : {}", job.name, account));
+            }
+        }
+        let (local, remote) = match job.direction {
+            JobDirection::Up => (&job.source, &job.target),
+            JobDirection::Down => (&job.target, &job.source),
+        };
+        if !local.starts_with("file://") {
+            problems.push(format!(
+                "任务 {} 的本地路径必须是file协议 : {}",
+                job.name, local
+            ));
+        }
+        if !remote.starts_with("adrive://") {
+            problems.push(format!(
+                "任务 {} 的云端路径必须是adrive协议 : {}",
+                job.name, remote
+            ));
+        }
+        if let Some(name) = &job.password_env {
+            if std::env::var_os(name).is_none() {
+                problems.push(format!("任务 {} 的密码环境变量未设置 : {}", job.name, name));
+            }
+        }
+        if let Some(path) = &job.password_file {
+            if !std::path::Path::new(path).is_file() {
+                problems.push(format!("任务 {} 的密码文件不存在 : {}", job.name, path));
+            }
+        }
+    }
+    if problems.is_empty() {
+        println!("配置文件正确");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(anyhow::anyhow!("配置文件有 {} 个问题", problems.len()))
+}
+
+async fn run_warp_server(bind: IpAddr, host: &str, port: u16) -> anyhow::Result<()> {
+    let routes = index().or(api());
+    let (_, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown((bind, port), AUTHORIZED.notified())
+        .with_context(|| format!("监听端口失败 : {}:{}", bind, port))?;
     println!(
-        "打开网页配置app以及账户信息： http://localhost:{}/html/index.html",
-        port
+        "打开网页配置app以及账户信息： http://{}:{}/html/index.html",
+        host, port
     );
-    println!("配置完成后，请按 Ctrl+C 停止服务");
-    let routes = index().or(api());
-    warp::serve(routes).run(([127, 0, 0, 1], port)).await;
+    println!(
+        "请在阿里云盘开发者中心配置回调地址为： {}",
+        server_config()?.redirect_uri
+    );
+    println!("授权成功后自动停止服务, 也可以按 Ctrl+C 停止");
+    server.await;
+    println!("授权成功, 已保存到配置文件");
     Ok(())
 }
 
@@ -47,7 +303,7 @@ fn index() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone
         .and(warp::get())
         .map(move |file_name: String| {
             if let Some(resource) = static_resource_map.get(file_name.as_str()) {
-                let mime = match file_name.split('.').last() {
+                let mime = match file_name.split('.').next_back() {
                     Some("html") => "text/html",
                     Some("css") => "text/css",
                     Some("js") => "application/javascript",
@@ -83,7 +339,13 @@ async fn get_client_info_body() -> Result<impl warp::Reply, Infallible> {
 
 async fn get_client_info_body_inner() -> anyhow::Result<Response<Body>> {
     let config = config::get_config().await?;
-    Ok(warp::reply::json(&config.app).into_response())
+    let app = config.app(account()).cloned().unwrap_or_default();
+    Ok(warp::reply::json(&json!({
+        "client_id": app.client_id,
+        "client_secret": app.client_secret,
+        "redirect_uri": server_config()?.redirect_uri,
+    }))
+    .into_response())
 }
 
 fn map_err(result: anyhow::Result<Response<Body>>) -> Result<impl warp::Reply, Infallible> {
@@ -113,7 +375,7 @@ async fn save_client_info_body(
 async fn save_client_info_body_inner(
     app_config: config::AppConfig,
 ) -> anyhow::Result<Response<Body>> {
-    config::set_app_config(app_config.clone()).await?;
+    config::set_app_config(account(), app_config.clone()).await?;
 
     let oauth_client = alipan::OAuthClient::default()
         .set_client_id(app_config.client_id.as_str())
@@ -121,12 +383,18 @@ async fn save_client_info_body_inner(
         .set_client_secret(app_config.client_secret.as_str())
         .await;
 
+    let server = server_config()?;
+    let state = random_state();
     let url = oauth_client
         .oauth_authorize()
         .await
-        .redirect_uri("http://localhost:58080/oauth_authorize")
-        .scope("user:base,file:all:read,file:all:write,album:shared:read")
+        .redirect_uri(server.redirect_uri.as_str())
+        .scope(server.scope.as_str())
+        .state(state.as_str())
         .build()?;
+    let pkce = Pkce::new();
+    let url = pkce.authorize_url(&url)?;
+    *PENDING_AUTHORIZE.lock().unwrap() = Some(PendingAuthorize { state, pkce });
 
     Ok(warp::reply::json(&json!({
         "url": url,
@@ -154,24 +422,25 @@ async fn oauth_authorize_body_inner(
         .get("code")
         .ok_or_else(|| anyhow::anyhow!("code 未找到"))?;
 
-    let app_config = config::get_app_config().await?;
-
-    let oauth_client = alipan::OAuthClient::default()
-        .set_client_id(app_config.client_id.as_str())
-        .await
-        .set_client_secret(app_config.client_secret.as_str())
-        .await;
-
-    let raw_token = oauth_client
-        .oauth_access_token()
-        .await
-        .grant_type(GrantType::AuthorizationCode)
-        .code(code.as_str())
-        .request()
-        .await?;
-
-    let access_token = alipan::AccessToken::wrap_oauth_token(raw_token);
-    config::set_access_token(access_token.clone()).await?;
+    // 只接受本次配置页面发起的授权, state 不匹配时保留正在进行的授权
+    let code_verifier = {
+        let mut pending = PENDING_AUTHORIZE.lock().unwrap();
+        let matched = pending
+            .as_ref()
+            .is_some_and(|p| query.get("state") == Some(&p.state));
+        if !matched {
+            return Ok(warp::reply::with_status(
+                warp::reply::html("state 不匹配, 请从配置页面重新开始授权"),
+                warp::http::StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+        pending.take().map(|p| p.pkce.verifier)
+    };
+    let app_config = config::get_app_config(account()).await?;
+    let access_token = access_token_by_code(&app_config, code, code_verifier.as_deref()).await?;
+    config::set_access_token(account(), access_token.clone()).await?;
+    AUTHORIZED.notify_one();
 
     Ok(warp::reply::html("认证成功，请关闭此页面").into_response())
 }
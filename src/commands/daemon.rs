@@ -0,0 +1,65 @@
+use crate::config;
+use crate::jobs::{run_on_schedule, validate_jobs, Schedule};
+use clap::Command;
+use std::collections::BTreeSet;
+
+pub const COMMAND_NAME: &str = "daemon";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME)
+}
+
+pub(crate) async fn run_sub_command(_args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let jobs = config::get_jobs().await?;
+    validate_jobs(&jobs)?;
+    // 启动时检查任务使用的账户, 需要重新登录时尽早提示, 不用等到任务运行
+    let accounts: BTreeSet<Option<String>> = jobs
+        .iter()
+        .filter(|job| job.schedule.is_some())
+        .map(|job| job.account.clone())
+        .collect();
+    for account in accounts {
+        let account = account.as_deref();
+        if let Err(err) = config::adrive_client_for_config(account).await {
+            tracing::error!(
+                "账户 {} 的token不可用 : {:#}",
+                account.unwrap_or("默认账户"),
+                err
+            );
+        }
+    }
+    let mut count = 0;
+    for job in jobs {
+        let Some(schedule) = &job.schedule else {
+            tracing::info!("任务没有设置计划, 只能使用 `run` 手动运行 : {}", job.name);
+            continue;
+        };
+        let schedule = Schedule::parse(schedule)?;
+        tracing::info!("任务计划 : {} ({:?})", job.name, schedule);
+        tokio::spawn(run_on_schedule(job, schedule));
+        count += 1;
+    }
+    if count == 0 {
+        return Err(anyhow::anyhow!("配置文件中没有设置了计划的任务"));
+    }
+    tracing::info!("守护进程已启动, 共 {} 个任务", count);
+    shutdown_signal().await?;
+    tracing::info!("守护进程停止");
+    Ok(())
+}
+
+/// 等待 Ctrl-C 或者 SIGTERM
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
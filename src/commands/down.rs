@@ -1,14 +1,22 @@
+use crate::bwlimit::{self, BandwidthSchedule, Limiter};
 use crate::common::{
-    check_passbook_password, delete_remote_file, find_passbook_folder, list_local_folder_file,
-    list_remote_folder_file,
+    check_passbook_password, count_remote_files_size, delete_remote_file, find_passbook_folder,
+    list_local_folder_file, list_remote_folder_file,
 };
-use crate::config::adrive_client_for_config;
+use crate::config::{adrive_client_for_config, selected_account};
 use crate::custom_crypto::{decrypt_file_name, decryptor_from_key};
+use crate::events;
+use crate::filter::{self, Filters};
+use crate::progress;
+use crate::quarantine::{Quarantine, DEFAULT_RETENTION_DAYS};
+use crate::report::SyncReport;
+use crate::retry::{check_status, retry, RetryRequest};
 use alipan::{AdriveClient, AdriveOpenFileType};
 use anyhow::Context;
 use chrono::TimeZone;
 use clap::{arg, Command};
 use futures_util::stream::TryStreamExt;
+use serde_json::json;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -18,7 +26,7 @@ use tokio_util::io::StreamReader;
 pub const COMMAND_NAME: &str = "down";
 
 pub fn command() -> Command {
-    Command::new(COMMAND_NAME).args(args())
+    Command::new(COMMAND_NAME).args(args()).args(filter::args())
 }
 
 fn args() -> Vec<clap::Arg> {
@@ -26,10 +34,40 @@ fn args() -> Vec<clap::Arg> {
         arg!(-s --source <SOURCE_PATH> "远端文件夹的地址, 例如 `adrive://drive_id/file_path`"),
         arg!(-t --target <CONFIG_FILE_PATH> "本地文件夹的地址, 例如 `file:///tmp/Backups`"),
         arg!(-p --password <PASSWORD> "云端加密密码").required(false),
+        arg!(--"no-delete" "只新增或更新本地文件, 不删除云端已不存在的本地文件"),
+        arg!(--"delete-permanently" "直接删除本地多余的文件, 不移入隔离区"),
+        arg!(--"quarantine-dir" <QUARANTINE_DIR> "隔离区文件夹, 默认是 `目标路径/.arsync/quarantine`")
+            .required(false),
+        arg!(--"quarantine-days" <DAYS> "隔离区保留天数, 0为永久保留, 默认是30").required(false),
+        arg!(--"keep-going" "单个文件失败时继续同步其他文件, 结束时汇总所有错误"),
+        arg!(--"no-progress" "不显示进度, 标准输出不是终端时不会显示进度"),
+        arg!(--bwlimit <RATE> "下载带宽限制, 例如 `1M`, 或者按时间段 `08:00,512K 18:00,10M 23:00,off`")
+            .required(false),
+        arg!(--events <FORMAT> "每个动作输出一行json事件到标准输出, 日志改为输出到标准错误")
+            .required(false)
+            .value_parser(["jsonl"]),
+        arg!(--report <REPORT_FILE> "同步结束后将统计写入json文件, 例如 `report.json`")
+            .required(false),
     ]
 }
 
+struct DownOptions {
+    no_delete: bool,
+    filters: Filters,
+    quarantine: Option<Quarantine>,
+    /// 本次运行的下载限速
+    bwlimit: Option<Limiter>,
+    report: Arc<SyncReport>,
+}
+
 pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let report = Arc::new(SyncReport::new(COMMAND_NAME, args.get_flag("keep-going")));
+    let result = down(args, Arc::clone(&report)).await;
+    progress::finish();
+    report.finish(result, args.get_one("report")).await
+}
+
+async fn down(args: &clap::ArgMatches, report: Arc<SyncReport>) -> anyhow::Result<()> {
     let source: &String = args
         .get_one("source")
         .with_context(|| "source is required")?;
@@ -42,14 +80,33 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
         url::Url::parse(target).with_context(|| format!("target url is invalid: {}", target))?;
     let source_path = source_url.path();
     let target_path = target_url.path();
+    if args.get_one::<String>("events").is_some() {
+        events::enable(target_path);
+    }
+    let bwlimit = args
+        .get_one::<String>("bwlimit")
+        .map(|v| {
+            BandwidthSchedule::parse(v)
+                .map(Limiter::new)
+                .with_context(|| format!("--bwlimit 不正确: {}", v))
+        })
+        .transpose()?;
     if !"adrive".eq(source_url.scheme()) {
         return Err(anyhow::anyhow!("原路径必须是adrive协议"));
     }
     if !"file".eq(target_url.scheme()) {
         return Err(anyhow::anyhow!("目标路径必须是file协议"));
     }
+    let quarantine = if args.get_flag("delete-permanently") {
+        None
+    } else {
+        Some(Quarantine::new(
+            target_path,
+            args.get_one::<String>("quarantine-dir").map(|v| v.as_str()),
+        )?)
+    };
     let source_sp = source_path.split('/').collect::<Vec<&str>>();
-    if source_sp.len() < 3 || source_sp[0] != "" {
+    if source_sp.len() < 3 || !source_sp[0].is_empty() {
         return Err(anyhow::anyhow!(
             "目标路径必须是 `adrive:///{{DriveID}}/{{文件夹路径}}`"
         ));
@@ -57,13 +114,13 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
     let drive_id = source_sp[1].to_owned();
     let folder_path = "/".to_owned() + &source_sp[2..].join("/").to_owned();
     let folder_path = urlencoding::decode(&folder_path)?.to_string();
-    let client = adrive_client_for_config().await?;
+    let client = adrive_client_for_config(selected_account(args)).await?;
     let folder_info = client
         .adrive_open_file_get_by_path()
         .await
         .drive_id(drive_id.clone())
         .file_path(folder_path)
-        .request()
+        .retry_request()
         .await?;
     if !AdriveOpenFileType::Folder.eq(&folder_info.r#type) {
         return Err(anyhow::anyhow!("原路径必须是一个文件夹"));
@@ -92,31 +149,64 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
             return Err(anyhow::anyhow!("云端无密码"));
         }
     }
+    if let Some(quarantine) = &quarantine {
+        let retention_days = args
+            .get_one::<String>("quarantine-days")
+            .map_or(DEFAULT_RETENTION_DAYS, |v| v.as_str())
+            .parse::<u64>()?;
+        quarantine.purge_expired(retention_days).await?;
+    }
+    let options = Arc::new(DownOptions {
+        no_delete: args.get_flag("no-delete"),
+        filters: Filters::from_args(args),
+        quarantine,
+        bwlimit,
+        report,
+    });
+    if progress::enabled() && !args.get_flag("no-progress") {
+        let (files, bytes) = count_remote_files_size(
+            Arc::clone(&client),
+            drive_id.clone(),
+            folder_info.file_id.clone(),
+        )
+        .await?;
+        progress::init(files, bytes);
+    }
     down_sync_folder(
         Arc::clone(&client),
         drive_id.clone(),
         folder_info.file_id,
         sync_password.clone(),
         target_path.to_owned(),
+        options,
     )
-    .await?;
-    Ok(())
+    .await
 }
 
 #[async_recursion::async_recursion]
+#[tracing::instrument(skip_all, fields(path = %target_path, file_id = %folder_id))]
 async fn down_sync_folder(
     client: Arc<AdriveClient>,
     drive_id: String,
     folder_id: String,
     sync_password: Option<Vec<u8>>,
     target_path: String,
+    options: Arc<DownOptions>,
 ) -> anyhow::Result<()> {
-    println!("从云端同步: {}", target_path);
+    tracing::info!("从云端同步: {}", target_path);
+    events::emit(
+        "scan-start",
+        json!({ "path": target_path, "remote_id": folder_id }),
+    );
     // 读取远端文件
     let mut open_file_list =
         list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+    options
+        .filters
+        .retain_remote(&mut open_file_list, &sync_password);
     // 读取本地的文件
     let mut metadata_list = list_local_folder_file(&target_path).await?;
+    options.filters.retain_local(&mut metadata_list);
     //
     let mut remote_delete = false;
     let mut local_delete = false;
@@ -131,10 +221,27 @@ async fn down_sync_folder(
                     name = decrypt_name;
                 }
                 Err(_) => {
-                    println!("解密文件名失败, 删除文件: {}", name);
+                    tracing::warn!("解密文件名失败, 删除文件: {}", name);
                     remote_delete = true;
-                    delete_remote_file(Arc::clone(&client), drive_id.clone(), x.file_id.clone())
-                        .await?;
+                    let result = delete_remote_file(
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        x.file_id.clone(),
+                    )
+                    .await;
+                    if options.report.check(&name, result)?.is_some() {
+                        options.report.add_deleted();
+                        events::emit(
+                            "delete",
+                            json!({
+                                "path": format!("{}/{}", target_path, name),
+                                "remote_id": x.file_id,
+                                "location": "remote",
+                                "action": "trash",
+                                "reason": "undecryptable",
+                            }),
+                        );
+                    }
                     continue;
                 }
             }
@@ -148,6 +255,7 @@ async fn down_sync_folder(
             }
         }
     }
+    let mut skipped_name_list = Vec::new();
     for (p, m) in &metadata_list {
         let mut delete = true;
         let file_name = p
@@ -156,6 +264,21 @@ async fn down_sync_folder(
             .to_str()
             .with_context(|| format!("文件名解析失败: {:?}", p))?
             .to_string();
+        if options.no_delete {
+            let remote_is_dir = remote_folder_list.contains(&file_name);
+            let remote_is_file = remote_file_date_map.contains_key(&file_name);
+            if !remote_is_dir && !remote_is_file {
+                // 云端已不存在, 保留本地文件
+                options.report.add_skipped();
+                continue;
+            }
+            if (m.is_dir() && remote_is_file) || (m.is_file() && remote_is_dir) {
+                tracing::warn!("跳过: {:?} (本地与云端类型不一致)", p);
+                options.report.add_skipped();
+                skipped_name_list.push(file_name);
+                continue;
+            }
+        }
         if m.is_dir() {
             if remote_folder_list.contains(&file_name) {
                 delete = false;
@@ -169,7 +292,7 @@ async fn down_sync_folder(
                 let md = chrono::Utc
                     .timestamp_opt(md.as_secs() as i64, md.subsec_nanos())
                     .unwrap();
-                let len = if let Some(_) = &sync_password {
+                let len = if sync_password.is_some() {
                     let len = m.len();
                     (len / (1 << 20) * ((1 << 20) + 16)) + (len % (1 << 20) + 16)
                 } else {
@@ -181,12 +304,43 @@ async fn down_sync_folder(
             }
         }
         if delete {
-            println!("删除: {:?}", p);
             local_delete = true;
-            if m.is_file() {
-                tokio::fs::remove_file(p).await?;
-            } else if m.is_dir() {
-                tokio::fs::remove_dir_all(p).await?;
+            let reason = if remote_folder_list.contains(&file_name)
+                || remote_file_date_map.contains_key(&file_name)
+            {
+                "outdated"
+            } else {
+                "removed"
+            };
+            let result = async {
+                if let Some(quarantine) = &options.quarantine {
+                    let dest = quarantine.move_in(p).await?;
+                    tracing::info!("移入隔离区: {:?} -> {:?}", p, dest);
+                } else {
+                    tracing::info!("删除: {:?}", p);
+                    if m.is_file() {
+                        tokio::fs::remove_file(p).await?;
+                    } else if m.is_dir() {
+                        tokio::fs::remove_dir_all(p).await?;
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            if options.report.check(p.display(), result)?.is_some() {
+                options.report.add_deleted();
+                events::emit(
+                    "delete",
+                    json!({
+                        "path": p,
+                        "location": "local",
+                        "action": if options.quarantine.is_some() { "quarantine" } else { "delete" },
+                        "reason": reason,
+                    }),
+                );
+            } else {
+                // 删除失败时不覆盖本地文件
+                skipped_name_list.push(file_name);
             }
         }
     }
@@ -195,10 +349,14 @@ async fn down_sync_folder(
     if remote_delete {
         open_file_list =
             list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+        options
+            .filters
+            .retain_remote(&mut open_file_list, &sync_password);
     }
     // 读取本地的文件
     if local_delete {
         metadata_list = list_local_folder_file(&target_path).await?;
+        options.filters.retain_local(&mut metadata_list);
     }
     // 下载
     // 阿里云盘限制：一分钟最多获取10次下载链接
@@ -221,67 +379,122 @@ async fn down_sync_folder(
                     name = decrypt_name;
                 }
                 Err(_) => {
-                    println!("解密文件名失败, 删除文件: {}", name);
-                    delete_remote_file(Arc::clone(&client), drive_id.clone(), x.file_id.clone())
-                        .await?;
+                    tracing::warn!("解密文件名失败, 删除文件: {}", name);
+                    let result = delete_remote_file(
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        x.file_id.clone(),
+                    )
+                    .await;
+                    if options.report.check(&name, result)?.is_some() {
+                        options.report.add_deleted();
+                        events::emit(
+                            "delete",
+                            json!({
+                                "path": format!("{}/{}", target_path, name),
+                                "remote_id": x.file_id,
+                                "location": "remote",
+                                "action": "trash",
+                                "reason": "undecryptable",
+                            }),
+                        );
+                    }
                     continue;
                 }
             }
         }
+        if skipped_name_list.contains(&name) {
+            continue;
+        }
         let path = std::path::Path::new(&target_path).join(&name);
         let path_string = path.to_str().unwrap().to_string();
         match x.r#type {
             AdriveOpenFileType::File => {
-                if !local_name_list.contains(&name) {
-                    down_file(
+                if local_name_list.contains(&name) {
+                    tracing::debug!("已同步, 跳过 : {}", path_string);
+                    options.report.add_skipped();
+                    progress::skip_file(x.size as u64);
+                } else {
+                    let _progress = progress::begin_file(&path_string, x.size as u64);
+                    events::transfer_start("download", &path_string, x.size as u64);
+                    let result = down_file(
                         Arc::clone(&client),
                         drive_id.clone(),
                         x.file_id.clone(),
                         sync_password.clone(),
-                        path_string,
+                        path_string.clone(),
+                        options.bwlimit.as_ref(),
                     )
-                    .await?;
+                    .await;
+                    if options.report.check(&path_string, result)?.is_some() {
+                        options.report.add_transferred(x.size as u64);
+                        events::transfer_done(json!({
+                            "path": path_string,
+                            "remote_id": x.file_id,
+                            "size": x.size,
+                        }));
+                    }
                 }
             }
             AdriveOpenFileType::Folder => {
-                if !local_name_list.contains(&name) {
-                    tokio::fs::create_dir_all(path).await?;
+                let result = async {
+                    if !local_name_list.contains(&name) {
+                        tokio::fs::create_dir_all(&path).await?;
+                        events::emit(
+                            "mkdir",
+                            json!({ "path": path_string, "remote_id": x.file_id, "location": "local" }),
+                        );
+                    }
+                    down_sync_folder(
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        x.file_id.clone(),
+                        sync_password.clone(),
+                        path_string.clone(),
+                        Arc::clone(&options),
+                    )
+                    .await
                 }
-                down_sync_folder(
-                    Arc::clone(&client),
-                    drive_id.clone(),
-                    x.file_id.clone(),
-                    sync_password.clone(),
-                    path_string,
-                )
-                .await?;
+                .await;
+                options.report.check(&path_string, result)?;
             }
         }
     }
     Ok(())
 }
 
-async fn down_file(
+#[tracing::instrument(skip_all, fields(path = %local_file_path, file_id = %file_id))]
+pub(crate) async fn down_file(
     client: Arc<AdriveClient>,
     drive_id: String,
     file_id: String,
     sync_password: Option<Vec<u8>>,
     local_file_path: String,
+    bwlimit: Option<&Limiter>,
 ) -> anyhow::Result<()> {
-    println!("从云端下载: {}", local_file_path);
+    tracing::info!("从云端下载: {}", local_file_path);
     let path_tmp = format!("{}.tmp", local_file_path);
-    let url = client
-        .adrive_open_file_get_download_url()
-        .await
-        .drive_id(drive_id)
-        .file_id(file_id)
-        .request()
-        .await?
-        .url;
-    if let Some(sync_password) = sync_password {
-        down_to_file_with_password(url, path_tmp.as_str(), sync_password).await?;
-    } else {
-        down_to_file(url, path_tmp.as_str()).await?;
+    // 下载地址会过期, 每次重试都重新获取下载地址, 并从头写入临时文件
+    let result = retry(|| async {
+        progress::restart_file();
+        let url = client
+            .adrive_open_file_get_download_url()
+            .await
+            .drive_id(drive_id.as_str())
+            .file_id(file_id.as_str())
+            .request()
+            .await?
+            .url;
+        if let Some(sync_password) = &sync_password {
+            down_to_file_with_password(url, path_tmp.as_str(), sync_password.clone(), bwlimit).await
+        } else {
+            down_to_file(url, path_tmp.as_str(), bwlimit).await
+        }
+    })
+    .await;
+    if let Err(err) = result {
+        let _ = tokio::fs::remove_file(path_tmp.as_str()).await;
+        return Err(err);
     }
     move_file(path_tmp.as_str(), local_file_path.as_str()).await?;
     Ok(())
@@ -292,11 +505,14 @@ async fn move_file(from: &str, to: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn down_to_file(url: String, path: &str) -> anyhow::Result<()> {
-    let mut stream = reqwest::get(url).await?.error_for_status()?.bytes_stream();
+async fn down_to_file(url: String, path: &str, bwlimit: Option<&Limiter>) -> anyhow::Result<()> {
+    let mut stream = check_status(reqwest::get(url).await?)?.bytes_stream();
     let mut file = tokio::fs::File::create(path).await?;
     while let Some(item) = stream.next().await {
-        file.write_all(&item?).await?;
+        let item = item?;
+        bwlimit::acquire(bwlimit, item.len()).await;
+        file.write_all(&item).await?;
+        progress::inc(item.len() as u64);
     }
     file.flush().await?;
     Ok(())
@@ -306,20 +522,21 @@ async fn down_to_file_with_password(
     url: String,
     path: &str,
     sync_password: Vec<u8>,
+    bwlimit: Option<&Limiter>,
 ) -> anyhow::Result<()> {
-    let stream = reqwest::get(url)
-        .await?
-        .error_for_status()?
+    let stream = check_status(reqwest::get(url).await?)?
         .bytes_stream()
         .map_err(convert_err);
     let mut reader = StreamReader::new(stream);
     let mut file = tokio::fs::File::create(path).await?;
     let mut decryptor = decryptor_from_key(sync_password.as_slice())?;
-    let mut buffer = [0u8; (1 << 20) + 16];
+    let mut buffer = vec![0u8; (1 << 20) + 16];
     let mut position = 0;
     loop {
         let n = reader.read(&mut buffer[position..]).await?;
         position += n;
+        bwlimit::acquire(bwlimit, n).await;
+        progress::inc(n as u64);
         if n == 0 {
             let item = decryptor
                 .decrypt_last(&buffer[..position])
@@ -0,0 +1,79 @@
+use crate::config;
+use crate::oauth::{access_token_by_code, qrcode_authorize, qrcode_status, Pkce, DEFAULT_SCOPE};
+use anyhow::Context;
+use clap::{arg, Command};
+use qrcode::render::unicode::Dense1x2;
+use std::time::Duration;
+
+pub const COMMAND_NAME: &str = "login";
+
+/// 阿里云盘要求轮询间隔不小于1秒
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME).args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--"no-qrcode" "不在终端中显示二维码, 只输出二维码的地址"),
+        arg!(--scope <SCOPE> "申请的授权范围, 多个用逗号分隔")
+            .required(false)
+            .default_value(DEFAULT_SCOPE),
+    ]
+}
+
+/// 在终端中扫码登录, 不需要浏览器和本地网页服务
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let account = config::selected_account(args);
+    let app = config::get_app_config(account).await?;
+    if app.client_id.is_empty() {
+        return Err(anyhow::anyhow!("请先使用 `config` 配置app的 client_id"));
+    }
+    let pkce = Pkce::new();
+    let scope: &String = args.get_one("scope").with_context(|| "scope is required")?;
+    let qrcode = qrcode_authorize(&app, scope, &pkce).await?;
+    let authorize_url = qrcode.authorize_url();
+    if !args.get_flag("no-qrcode") {
+        let image = qrcode::QrCode::new(authorize_url.as_bytes())?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        println!("{}", image);
+    }
+    println!(
+        "请使用阿里云盘App扫描二维码, 或者在已登录阿里云盘的浏览器中打开 : {}",
+        authorize_url
+    );
+    println!("二维码图片 : {}", qrcode.qr_code_url);
+    let mut scanned = false;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let status = qrcode_status(&qrcode.sid).await?;
+        match status.status.as_str() {
+            "WaitLogin" => {}
+            "ScanSuccess" => {
+                if !scanned {
+                    scanned = true;
+                    println!("已扫码, 请在App中确认授权");
+                }
+            }
+            "LoginSuccess" => {
+                let code = status
+                    .auth_code
+                    .ok_or_else(|| anyhow::anyhow!("登录成功但没有返回授权码"))?;
+                let code_verifier = app
+                    .client_secret
+                    .is_empty()
+                    .then_some(pkce.verifier.as_str());
+                let access_token = access_token_by_code(&app, &code, code_verifier).await?;
+                config::set_access_token(account, access_token).await?;
+                println!("登录成功");
+                return Ok(());
+            }
+            "QRCodeExpired" => return Err(anyhow::anyhow!("二维码已过期, 请重新运行 `login`")),
+            other => return Err(anyhow::anyhow!("未知的二维码状态 : {}", other)),
+        }
+    }
+}
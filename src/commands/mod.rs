@@ -1,5 +1,11 @@
-mod access_token_loader;
 pub(crate) mod config;
+pub(crate) mod daemon;
 pub(crate) mod down;
 pub(crate) mod drives;
+pub(crate) mod login;
+pub(crate) mod prune;
+pub(crate) mod restore;
+pub(crate) mod run;
+pub(crate) mod status;
 pub(crate) mod up;
+pub(crate) mod versions;
@@ -0,0 +1,237 @@
+use crate::common::{delete_remote_file, get_remote_folder, open_remote_folder, parse_adrive_uri};
+use crate::config::{adrive_client_for_config, selected_account};
+use crate::versions::{list_file_versions, list_snapshots, RetentionPolicy};
+use anyhow::Context;
+use chrono::{DateTime, Local, Utc};
+use clap::{arg, Command};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub const COMMAND_NAME: &str = "prune";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME).args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(-s --source <SOURCE_PATH> "远端文件夹的地址, 例如 `adrive:///drive_id/file_path`"),
+        arg!(-p --password <PASSWORD> "云端加密密码, 按文件清理时需要").required(false),
+        arg!(--"keep-last" <N> "保留最近的N个").required(false),
+        arg!(--"keep-daily" <N> "保留最近N天, 每天最新的一个").required(false),
+        arg!(--"keep-weekly" <N> "保留最近N周, 每周最新的一个").required(false),
+        arg!(--"keep-monthly" <N> "保留最近N个月, 每月最新的一个").required(false),
+        arg!(--per <MODE> "`snapshot` 按快照清理(默认), `file` 按文件清理")
+            .required(false)
+            .value_parser(["snapshot", "file"]),
+        arg!(--"dry-run" "只显示将要删除的历史版本, 不删除"),
+    ]
+}
+
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let source: &String = args
+        .get_one("source")
+        .with_context(|| "source is required")?;
+    let policy = RetentionPolicy {
+        keep_last: parse_count(args, "keep-last")?,
+        keep_daily: parse_count(args, "keep-daily")?,
+        keep_weekly: parse_count(args, "keep-weekly")?,
+        keep_monthly: parse_count(args, "keep-monthly")?,
+    };
+    if policy.is_empty() {
+        return Err(anyhow::anyhow!(
+            "至少需要一条保留策略, 例如 `--keep-daily 7`"
+        ));
+    }
+    let dry_run = args.get_flag("dry-run");
+    let (drive_id, folder_path) = parse_adrive_uri(source)?;
+    let per = args
+        .get_one::<String>("per")
+        .map_or("snapshot", |v| v.as_str());
+    let client = adrive_client_for_config(selected_account(args)).await?;
+    // 按快照清理时不需要解密文件名, 不检查密码
+    let (folder_info, sync_password) = if per == "snapshot" {
+        let folder_info = get_remote_folder(&client, drive_id.clone(), folder_path).await?;
+        (folder_info, None)
+    } else {
+        open_remote_folder(
+            &client,
+            drive_id.clone(),
+            folder_path,
+            args.get_one("password"),
+        )
+        .await?
+    };
+    let snapshots = list_snapshots(&client, drive_id.clone(), folder_info.file_id.clone()).await?;
+    // 需要删除的文件夹或文件 (file_id, 说明)
+    let mut delete_list = vec![];
+    if per == "snapshot" {
+        let times = snapshots.iter().map(|x| x.time).collect::<Vec<_>>();
+        let keep = policy.keep(&times);
+        for (snapshot, keep) in snapshots.iter().zip(keep) {
+            if !keep {
+                delete_list.push((
+                    snapshot.folder.file_id.clone(),
+                    format!("快照 {}", format_time(snapshot.time)),
+                ));
+            }
+        }
+    } else {
+        let versions = list_file_versions(
+            &client,
+            drive_id.clone(),
+            folder_info.file_id,
+            sync_password,
+        )
+        .await?
+        .into_iter()
+        .filter(|x| x.valid_until.is_some())
+        .collect::<Vec<_>>();
+        let keys = versions
+            .iter()
+            .map(|x| (x.path.as_str(), x.valid_from, x.valid_until))
+            .collect::<Vec<_>>();
+        let times = snapshots.iter().map(|x| x.time).collect::<Vec<_>>();
+        for item in select_per_file(&policy, &times, &keys) {
+            match item {
+                Prune::Snapshot(i) => delete_list.push((
+                    snapshots[i].folder.file_id.clone(),
+                    format!("快照 {}", format_time(snapshots[i].time)),
+                )),
+                Prune::Version { snapshot, version } => delete_list.push((
+                    versions[version].file.file_id.clone(),
+                    format!(
+                        "{} {}",
+                        format_time(snapshots[snapshot].time),
+                        versions[version].path
+                    ),
+                )),
+            }
+        }
+    }
+    for (file_id, description) in &delete_list {
+        if dry_run {
+            tracing::info!("将删除历史版本 : {}", description);
+        } else {
+            tracing::info!("删除历史版本 : {}", description);
+            delete_remote_file(Arc::clone(&client), drive_id.clone(), file_id.clone()).await?;
+        }
+    }
+    tracing::info!("共 {} 个历史版本需要删除", delete_list.len());
+    Ok(())
+}
+
+/// 按文件清理时需要删除的快照或者历史版本(下标)
+#[derive(Debug, PartialEq)]
+enum Prune {
+    Snapshot(usize),
+    Version { snapshot: usize, version: usize },
+}
+
+/// 历史版本的 (路径, valid_from, valid_until)
+type VersionKey<'a> = (&'a str, DateTime<Utc>, Option<DateTime<Utc>>);
+
+/// 每个文件的历史版本分别按保留策略清理,
+/// 快照中的文件全部需要删除时直接删除快照文件夹, 没有可以解密的文件的快照不删除
+fn select_per_file(
+    policy: &RetentionPolicy,
+    snapshot_times: &[DateTime<Utc>],
+    versions: &[VersionKey],
+) -> Vec<Prune> {
+    let mut path_map = HashMap::<&str, Vec<usize>>::new();
+    for (i, (path, _, _)) in versions.iter().enumerate() {
+        path_map.entry(path).or_default().push(i);
+    }
+    let mut delete_index = vec![false; versions.len()];
+    for indexes in path_map.values() {
+        let times = indexes.iter().map(|i| versions[*i].1).collect::<Vec<_>>();
+        for (i, keep) in indexes.iter().zip(policy.keep(&times)) {
+            delete_index[*i] = !keep;
+        }
+    }
+    let mut selected = vec![];
+    for (snapshot, time) in snapshot_times.iter().enumerate() {
+        let indexes = (0..versions.len())
+            .filter(|i| versions[*i].2 == Some(*time))
+            .collect::<Vec<_>>();
+        if !indexes.is_empty() && indexes.iter().all(|i| delete_index[*i]) {
+            selected.push(Prune::Snapshot(snapshot));
+            continue;
+        }
+        selected.extend(
+            indexes
+                .into_iter()
+                .filter(|i| delete_index[*i])
+                .map(|version| Prune::Version { snapshot, version }),
+        );
+    }
+    selected
+}
+
+fn parse_count(args: &clap::ArgMatches, id: &str) -> anyhow::Result<usize> {
+    Ok(args
+        .get_one::<String>(id)
+        .map(|v| v.parse::<usize>())
+        .transpose()
+        .with_context(|| format!("{} 必须是数字", id))?
+        .unwrap_or(0))
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 7, day, 12, 0, 0).unwrap()
+    }
+
+    fn keep_last(n: usize) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last: n,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn deletes_snapshot_when_all_files_pruned() {
+        let versions = [
+            ("a", time(1), Some(time(2))),
+            ("a", time(2), Some(time(3))),
+            ("b", time(1), Some(time(3))),
+        ];
+        let selected = select_per_file(&keep_last(1), &[time(2), time(3)], &versions);
+        assert_eq!(selected, vec![Prune::Snapshot(0)]);
+    }
+
+    #[test]
+    fn deletes_single_versions_when_snapshot_keeps_files() {
+        let versions = [
+            ("a", time(1), Some(time(3))),
+            ("a", time(3), Some(time(4))),
+            ("b", time(2), Some(time(3))),
+        ];
+        let selected = select_per_file(&keep_last(1), &[time(3), time(4)], &versions);
+        assert_eq!(
+            selected,
+            vec![Prune::Version {
+                snapshot: 0,
+                version: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_snapshot_without_files() {
+        // 没有可以解密的文件(例如其他密码上传的)的快照不删除
+        let versions = [("a", time(1), Some(time(3)))];
+        let selected = select_per_file(&keep_last(1), &[time(2), time(3)], &versions);
+        assert!(selected.is_empty());
+    }
+}
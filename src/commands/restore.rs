@@ -0,0 +1,86 @@
+use crate::commands::down::down_file;
+use crate::common::{open_remote_folder, parse_adrive_uri};
+use crate::config::{adrive_client_for_config, selected_account};
+use crate::versions::{list_file_versions, parse_time, versions_at};
+use anyhow::Context;
+use chrono::Local;
+use clap::{arg, Command};
+use std::sync::Arc;
+
+pub const COMMAND_NAME: &str = "restore";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME).args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(-s --source <SOURCE_PATH> "远端文件夹的地址, 例如 `adrive:///drive_id/file_path`"),
+        arg!(-t --target <TARGET_PATH> "本地文件夹的地址, 例如 `file:///tmp/Restore`"),
+        arg!(-p --password <PASSWORD> "云端加密密码").required(false),
+        arg!(--at <TIME> "恢复到这个时间的状态, 例如 `2024-07-01 12:00:00`"),
+    ]
+}
+
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let source: &String = args
+        .get_one("source")
+        .with_context(|| "source is required")?;
+    let target: &String = args
+        .get_one("target")
+        .with_context(|| "target is required")?;
+    let at: &String = args.get_one("at").with_context(|| "at is required")?;
+    let at = parse_time(at)?;
+    let target_url =
+        url::Url::parse(target).with_context(|| format!("target url is invalid: {}", target))?;
+    if !"file".eq(target_url.scheme()) {
+        return Err(anyhow::anyhow!("目标路径必须是file协议"));
+    }
+    let target_path = target_url.path();
+    let metadata = tokio::fs::metadata(target_path)
+        .await
+        .with_context(|| format!("目标路径未找到 : {}", target_path))?;
+    if !metadata.is_dir() {
+        return Err(anyhow::anyhow!("目标路径必须是文件夹"));
+    }
+    let (drive_id, folder_path) = parse_adrive_uri(source)?;
+    let client = adrive_client_for_config(selected_account(args)).await?;
+    let (folder_info, sync_password) = open_remote_folder(
+        &client,
+        drive_id.clone(),
+        folder_path,
+        args.get_one("password"),
+    )
+    .await?;
+    let versions = list_file_versions(
+        &client,
+        drive_id.clone(),
+        folder_info.file_id,
+        sync_password.clone(),
+    )
+    .await?;
+    let selected = versions_at(&versions, at);
+    tracing::info!(
+        "恢复到 {} 的状态, 共 {} 个文件",
+        at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+        selected.len()
+    );
+    for version in selected {
+        let path = std::path::Path::new(target_path).join(&version.path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        down_file(
+            Arc::clone(&client),
+            drive_id.clone(),
+            version.file.file_id.clone(),
+            sync_password.clone(),
+            path.to_str()
+                .with_context(|| "file name is invalid")?
+                .to_string(),
+            None,
+        )
+        .await?;
+    }
+    Ok(())
+}
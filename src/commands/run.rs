@@ -0,0 +1,22 @@
+use crate::jobs::{find_job, run_job};
+use anyhow::Context;
+use clap::{arg, Command};
+
+pub const COMMAND_NAME: &str = "run";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME).args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![arg!(<JOB> "配置文件中的任务名称")]
+}
+
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let name: &String = args.get_one("JOB").with_context(|| "job is required")?;
+    let job = find_job(name).await?;
+    if !run_job(&job).await? {
+        return Err(anyhow::anyhow!("任务正在运行 : {}", name));
+    }
+    Ok(())
+}
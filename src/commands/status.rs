@@ -0,0 +1,122 @@
+use crate::config::{self, adrive_client, check_access_token, AppConfig};
+use alipan::AccessToken;
+use chrono::{Local, TimeZone, Utc};
+use clap::{arg, Command};
+
+pub const COMMAND_NAME: &str = "status";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME)
+        .visible_alias("whoami")
+        .about("显示账户的登录状态和token的过期时间")
+        .args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![arg!(--"no-check" "只显示配置文件中的信息, 不请求阿里云盘检查token")]
+}
+
+/// 显示 `--account` 选择的账户, 没有选择时显示所有账户
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let selected = config::selected_account(args);
+    let config = config::get_config().await?;
+    let accounts: Vec<Option<String>> = match selected {
+        Some(name) => {
+            config
+                .app(Some(name))
+                .ok_or_else(|| anyhow::anyhow!("配置文件中没有这个账户 : {}", name))?;
+            vec![Some(name.to_string())]
+        }
+        // 有其他账户时, 没有配置的默认账户不显示
+        None => config
+            .all_accounts()
+            .filter(|(name, app, _)| {
+                name.is_some() || config.accounts.is_empty() || !app.client_id.is_empty()
+            })
+            .map(|(name, _, _)| name.map(str::to_string))
+            .collect(),
+    };
+    let check = !args.get_flag("no-check");
+    let mut failed = 0;
+    for (index, account) in accounts.iter().enumerate() {
+        let account = account.as_deref();
+        if index > 0 {
+            println!();
+        }
+        println!("账户 : {}", account.unwrap_or("默认账户"));
+        if check {
+            match adrive_client(account).await {
+                Ok(client) => match check_access_token(&client, account).await {
+                    Ok(user) => println!("用户 : {} ({})", user.name, user.id),
+                    Err(err) => {
+                        failed += 1;
+                        println!("检查失败 : {:#}", err);
+                    }
+                },
+                Err(err) => {
+                    failed += 1;
+                    println!("检查失败 : {:#}", err);
+                }
+            }
+        }
+        // 检查时可能刷新了token, 显示刷新后的
+        print_account(
+            &config::get_app_config(account).await?,
+            &config::get_access_token(account).await?,
+        );
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} 个账户的token不可用", failed));
+    }
+    Ok(())
+}
+
+fn print_account(app: &AppConfig, token: &AccessToken) {
+    if app.client_id.is_empty() {
+        println!("client_id : 没有配置");
+    } else if app.client_secret.is_empty() {
+        println!("client_id : {} (PKCE)", app.client_id);
+    } else {
+        println!("client_id : {}", app.client_id);
+    }
+    if token.refresh_token.is_empty() {
+        if token.access_token.is_empty() {
+            println!("token : 没有登录");
+        } else {
+            // 环境变量中只有 access_token
+            println!("token : 没有 refresh_token, 过期后不会自动刷新");
+        }
+        return;
+    }
+    println!("token获取时间 : {}", format_timestamp(token.created_at));
+    let expires_at = token.created_at + token.expires_in;
+    let remaining = expires_at - Utc::now().timestamp();
+    if remaining > 0 {
+        println!(
+            "access_token过期时间 : {} (剩余 {})",
+            format_timestamp(expires_at),
+            format_duration(remaining)
+        );
+    } else {
+        println!(
+            "access_token过期时间 : {} (已过期, 使用时自动刷新)",
+            format_timestamp(expires_at)
+        );
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    Local.timestamp_opt(timestamp, 0).single().map_or_else(
+        || timestamp.to_string(),
+        |time| time.format("%Y-%m-%d %H:%M:%S").to_string(),
+    )
+}
+
+fn format_duration(seconds: i64) -> String {
+    let (hours, minutes) = (seconds / 3600, seconds % 3600 / 60);
+    if hours > 0 {
+        format!("{}小时{}分钟", hours, minutes)
+    } else {
+        format!("{}分钟", minutes.max(1))
+    }
+}
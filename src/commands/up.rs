@@ -1,24 +1,37 @@
+use crate::bwlimit::{self, BandwidthSchedule, Limiter};
 use crate::common::{
-    check_passbook_password, create_passbook_password, delete_remote_file, find_passbook_folder,
+    check_passbook_password, complete_remote_file, count_local_files, create_passbook_password,
+    create_remote_file, delete_remote_file, find_passbook_folder, find_remote_file,
     list_local_folder_file, list_remote_folder_file,
 };
-use crate::config::adrive_client_for_config;
+use crate::config::{adrive_client_for_config, selected_account};
 use crate::custom_crypto::{decrypt_file_name, encrypt_file_name, encryptor_from_key};
+use crate::events;
+use crate::filter::{self, Filters};
+use crate::progress;
+use crate::report::SyncReport;
+use crate::retry::{check_status, retry, HttpStatusError, RetryRequest};
+use crate::versions::RemoteVersions;
+#[cfg(target_os = "linux")]
+use crate::watch::{LocalWatcher, WatchChanges};
 use alipan::response::AdriveOpenFile;
 use alipan::AdriveOpenFileType::Folder;
 use alipan::{AdriveClient, AdriveOpenFilePartInfoCreate, AdriveOpenFileType, CheckNameMode};
 use anyhow::Context;
 use chrono::{TimeZone, Utc};
 use clap::{arg, Command};
+use serde_json::json;
 use sha1::Digest;
 use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 
 pub const COMMAND_NAME: &str = "up";
 
 pub fn command() -> Command {
-    Command::new(COMMAND_NAME).args(args())
+    Command::new(COMMAND_NAME).args(args()).args(filter::args())
 }
 
 fn args() -> Vec<clap::Arg> {
@@ -26,10 +39,103 @@ fn args() -> Vec<clap::Arg> {
         arg!(-s --source <SOURCE_PATH> "本地文件夹的URI, 例如 `file:///tmp/Backups`"),
         arg!(-t --target <CONFIG_FILE_PATH> "remote target uri, like `adrive://drive_id/file_path`"),
         arg!(-p --password <PASSWORD> "password for adrive folder encryption").required(false),
+        arg!(--"no-delete" "只新增或更新云端文件, 不删除本地已不存在的云端文件"),
+        arg!(--versions "被替换或删除的云端文件移动到 `.arsync/versions` 保留历史版本, 而不是放入回收站"),
+        arg!(--force "跳过删除数量的安全检查, 允许原路径为空"),
+        arg!(--"max-delete" <COUNT> "最多允许删除的云端文件数量, 超过则中止同步").required(false),
+        arg!(--"max-delete-percent" <PERCENT> "最多允许删除的云端文件百分比, 默认是50")
+            .required(false),
+        arg!(--"keep-going" "单个文件失败时继续同步其他文件, 结束时汇总所有错误"),
+        arg!(--"no-progress" "不显示进度, 标准输出不是终端时不会显示进度"),
+        arg!(--bwlimit <RATE> "上传带宽限制, 例如 `1M`, 或者按时间段 `08:00,512K 18:00,10M 23:00,off`")
+            .required(false),
+        arg!(--events <FORMAT> "每个动作输出一行json事件到标准输出, 日志改为输出到标准错误")
+            .required(false)
+            .value_parser(["jsonl"]),
+        arg!(--report <REPORT_FILE> "同步结束后将统计写入json文件, 例如 `report.json`")
+            .required(false),
+        arg!(--watch "同步后持续监听本地文件夹(仅支持Linux), 只同步发生变化的文件夹"),
+        arg!(--"watch-debounce" <SECONDS> "文件变化后等待多少秒没有新的变化再同步, 默认是2")
+            .required(false),
+        arg!(--"watch-full-interval" <SECONDS> "监听时每隔多少秒完整同步一次, 默认是3600")
+            .required(false),
     ]
 }
 
+struct UpOptions {
+    no_delete: bool,
+    /// 删除云端文件前的安全检查, `--no-delete` 或者 `--force` 时为 None
+    deletion_limit: Option<DeletionLimit>,
+    filters: Filters,
+    versions: Option<RemoteVersions>,
+    /// 本次运行的上传限速, 持续同步的每一次同步共享
+    bwlimit: Option<Arc<Limiter>>,
+    report: Arc<SyncReport>,
+}
+
+#[derive(Debug, Clone)]
+struct DeletionLimit {
+    max_delete: Option<u64>,
+    max_delete_percent: f64,
+}
+
+impl DeletionLimit {
+    /// 将删除 deleted / total 个云端文件时, 超过安全阈值则返回错误
+    fn check(&self, deleted: u64, total: u64, source_path: &str) -> anyhow::Result<()> {
+        let exceed_count = self.max_delete.is_some_and(|max| deleted > max);
+        let exceed_percent =
+            total > 0 && deleted as f64 * 100.0 / total as f64 > self.max_delete_percent;
+        if exceed_count || exceed_percent {
+            return Err(anyhow::anyhow!(
+                "将删除 {} / {} 个云端文件, 超过安全阈值, 确认无误请使用 --force : {}",
+                deleted,
+                total,
+                source_path
+            ));
+        }
+        Ok(())
+    }
+
+    fn from_args(args: &clap::ArgMatches) -> anyhow::Result<Option<Self>> {
+        if args.get_flag("no-delete") || args.get_flag("force") {
+            return Ok(None);
+        }
+        Ok(Some(DeletionLimit {
+            max_delete: args
+                .get_one::<String>("max-delete")
+                .map(|v| v.parse::<u64>())
+                .transpose()?,
+            max_delete_percent: args
+                .get_one::<String>("max-delete-percent")
+                .map_or("50", |v| v.as_str())
+                .parse::<f64>()?,
+        }))
+    }
+}
+
+impl UpOptions {
+    /// 持续同步时每一次同步使用新的历史版本快照, 同一个文件多次变化时每次都能保留
+    #[cfg(target_os = "linux")]
+    fn next_cycle(&self) -> UpOptions {
+        UpOptions {
+            no_delete: self.no_delete,
+            deletion_limit: self.deletion_limit.clone(),
+            filters: self.filters.clone(),
+            versions: self.versions.as_ref().map(RemoteVersions::next_snapshot),
+            bwlimit: self.bwlimit.clone(),
+            report: Arc::clone(&self.report),
+        }
+    }
+}
+
 pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let report = Arc::new(SyncReport::new(COMMAND_NAME, args.get_flag("keep-going")));
+    let result = up(args, Arc::clone(&report)).await;
+    progress::finish();
+    report.finish(result, args.get_one("report")).await
+}
+
+async fn up(args: &clap::ArgMatches, report: Arc<SyncReport>) -> anyhow::Result<()> {
     let source: &String = args
         .get_one("source")
         .with_context(|| "source is required")?;
@@ -42,6 +148,17 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
         url::Url::parse(target).with_context(|| format!("target url is invalid: {}", target))?;
     let source_path = source_url.path();
     let target_path = target_url.path();
+    if args.get_one::<String>("events").is_some() {
+        events::enable(source_path);
+    }
+    let bwlimit = args
+        .get_one::<String>("bwlimit")
+        .map(|v| {
+            BandwidthSchedule::parse(v)
+                .map(|x| Arc::new(Limiter::new(x)))
+                .with_context(|| format!("--bwlimit 不正确: {}", v))
+        })
+        .transpose()?;
     if !"file".eq(source_url.scheme()) {
         return Err(anyhow::anyhow!("原路径必须是file协议"));
     }
@@ -55,7 +172,7 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
         return Err(anyhow::anyhow!("目标路径必须是adrive协议"));
     }
     let target_sp = target_path.split('/').collect::<Vec<&str>>();
-    if target_sp.len() < 3 || target_sp[0] != "" {
+    if target_sp.len() < 3 || !target_sp[0].is_empty() {
         return Err(anyhow::anyhow!(
             "目标路径必须是 `adrive:///{{DriveID}}/{{文件夹路径}}`"
         ));
@@ -64,19 +181,19 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
     let folder_path = "/".to_owned() + &target_sp[2..].join("/").to_owned();
     // url解码
     let folder_path = urlencoding::decode(&folder_path)?.to_string();
-    let client = adrive_client_for_config().await?;
+    let client = adrive_client_for_config(selected_account(args)).await?;
     let folder_info = client
         .adrive_open_file_get_by_path()
         .await
         .drive_id(drive_id.clone())
         .file_path(folder_path)
-        .request()
+        .retry_request()
         .await?;
     if !Folder.eq(&folder_info.r#type) {
         return Err(anyhow::anyhow!("目标路径必须是一个文件夹"));
     }
     let password: Option<&String> = args.get_one("password");
-    let password = password.map(|p| p.clone());
+    let password = password.cloned();
     let mut sync_password: Option<Vec<u8>> = None;
     if let Some(password) = password {
         let (passbook, other_files) =
@@ -104,110 +221,366 @@ pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<(
             }
         }
     }
+    let versions = if args.get_flag("versions") {
+        Some(RemoteVersions::new(
+            Arc::clone(&client),
+            drive_id.clone(),
+            folder_info.file_id.clone(),
+        ))
+    } else {
+        None
+    };
+    let options = Arc::new(UpOptions {
+        no_delete: args.get_flag("no-delete"),
+        deletion_limit: DeletionLimit::from_args(args)?,
+        filters: Filters::from_args(args),
+        versions,
+        bwlimit,
+        report,
+    });
+    // 在第一次同步之前开始监听, 避免遗漏同步过程中的变化
+    let watcher = if args.get_flag("watch") {
+        #[cfg(target_os = "linux")]
+        {
+            Some(LocalWatcher::new(source_path)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            return Err(anyhow::anyhow!("--watch 仅支持Linux"));
+        }
+    } else {
+        None
+    };
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    let remote_total = check_deletion_threshold(
+        source_path,
+        Arc::clone(&client),
+        drive_id.clone(),
+        folder_info.file_id.clone(),
+        sync_password.clone(),
+        &options,
+    )
+    .await?;
+    if watcher.is_none() && progress::enabled() && !args.get_flag("no-progress") {
+        let (files, bytes) = count_local_files(source_path.to_owned()).await?;
+        progress::init(files, bytes);
+    }
     up_sync_folder(
         source_path.to_owned(),
         Arc::clone(&client),
         drive_id.clone(),
-        folder_info.file_id,
+        folder_info.file_id.clone(),
+        vec![],
         sync_password.clone(),
+        Arc::clone(&options),
+        true,
+    )
+    .await?;
+    #[cfg(target_os = "linux")]
+    if let Some(watcher) = watcher {
+        watch_and_sync(
+            args,
+            watcher,
+            source_path,
+            client,
+            drive_id,
+            folder_info.file_id,
+            sync_password,
+            options,
+            remote_total,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// 持续监听本地文件夹, 同步发生变化的文件夹, 定期完整同步以防遗漏事件, Ctrl-C 时结束
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+async fn watch_and_sync(
+    args: &clap::ArgMatches,
+    mut watcher: LocalWatcher,
+    source_path: &str,
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    sync_password: Option<Vec<u8>>,
+    mut options: Arc<UpOptions>,
+    mut remote_total: u64,
+) -> anyhow::Result<()> {
+    let debounce = args
+        .get_one::<String>("watch-debounce")
+        .map_or("2", |v| v.as_str())
+        .parse::<u64>()
+        .with_context(|| "watch-debounce 必须是数字")?;
+    let full_interval = args
+        .get_one::<String>("watch-full-interval")
+        .map_or("3600", |v| v.as_str())
+        .parse::<u64>()
+        .with_context(|| "watch-full-interval 必须是数字")?;
+    let debounce = std::time::Duration::from_secs(debounce);
+    let full_interval = std::time::Duration::from_secs(full_interval.max(1));
+    let mut full_sync =
+        tokio::time::interval_at(tokio::time::Instant::now() + full_interval, full_interval);
+    // 在循环之前监听, 同步过程中按下的 Ctrl-C 在同步结束后处理
+    let mut interrupt = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
+    tracing::info!("开始监听本地文件夹 : {}", source_path);
+    loop {
+        let folders = tokio::select! {
+            biased;
+            _ = interrupt.recv() => {
+                tracing::info!("停止监听 : {}", source_path);
+                return Ok(());
+            }
+            _ = full_sync.tick() => None,
+            changes = watcher.next_changes(debounce) => match changes? {
+                WatchChanges::Folders(folders) => Some(folders),
+                WatchChanges::Overflow => {
+                    tracing::warn!("文件变化过多, 部分事件丢失, 进行完整同步");
+                    None
+                }
+            },
+        };
+        options = Arc::new(options.next_cycle());
+        let result = async {
+            match folders {
+                Some(folders) => {
+                    for folder in folders {
+                        up_sync_changed_folder(
+                            source_path,
+                            &folder,
+                            Arc::clone(&client),
+                            drive_id.clone(),
+                            folder_id.clone(),
+                            sync_password.clone(),
+                            Arc::clone(&options),
+                            remote_total,
+                        )
+                        .await?;
+                    }
+                }
+                None => {
+                    watcher.rewatch()?;
+                    remote_total = check_deletion_threshold(
+                        source_path,
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        folder_id.clone(),
+                        sync_password.clone(),
+                        &options,
+                    )
+                    .await?;
+                    up_sync_folder(
+                        source_path.to_owned(),
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        folder_id.clone(),
+                        vec![],
+                        sync_password.clone(),
+                        Arc::clone(&options),
+                        true,
+                    )
+                    .await?;
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!("同步失败, 等待下次同步 : {:#}", err);
+        }
+    }
+}
+
+/// 只同步一个发生变化的文件夹(不包括已经存在的子文件夹)
+///
+/// remote_total 为上一次完整同步时云端的文件数量, 删除的比例按整个目标文件夹计算
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+async fn up_sync_changed_folder(
+    source_path: &str,
+    folder: &std::path::Path,
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    root_folder_id: String,
+    sync_password: Option<Vec<u8>>,
+    options: Arc<UpOptions>,
+    remote_total: u64,
+) -> anyhow::Result<()> {
+    if !folder.is_dir() {
+        // 文件夹已被删除, 由上级文件夹处理
+        return Ok(());
+    }
+    let relative = folder.strip_prefix(source_path)?;
+    let mut folder_id = root_folder_id;
+    let mut remote_path = vec![];
+    for name in relative.iter() {
+        let name = name.to_str().with_context(|| "file name is invalid")?;
+        if options.filters.is_excluded(name) {
+            return Ok(());
+        }
+        let remote_name = match &sync_password {
+            Some(password) => encrypt_file_name(name, password)?,
+            None => name.to_string(),
+        };
+        match find_remote_file(&client, drive_id.clone(), folder_id, &remote_name).await? {
+            Some(remote) if Folder.eq(&remote.r#type) => folder_id = remote.file_id,
+            _ => {
+                // 云端还没有这个文件夹, 由上级文件夹创建并完整同步
+                tracing::debug!("云端文件夹不存在, 跳过 : {:?}", folder);
+                return Ok(());
+            }
+        }
+        remote_path.push(remote_name);
+    }
+    let folder = folder.to_str().with_context(|| "file name is invalid")?;
+    if let Some(limit) = &options.deletion_limit {
+        // 只同步这个文件夹, 只统计这个文件夹中的删除
+        let (deleted, total) = count_remote_deletions(
+            folder.to_owned(),
+            Arc::clone(&client),
+            drive_id.clone(),
+            folder_id.clone(),
+            sync_password.clone(),
+            &options.filters,
+            false,
+        )
+        .await?;
+        // 子文件夹被清空是正常的删除, 只有目标文件夹本身为空时才可能是磁盘未挂载
+        if relative.as_os_str().is_empty() {
+            check_source_not_empty(folder, total).await?;
+        }
+        limit.check(deleted, remote_total.max(total), folder)?;
+    }
+    up_sync_folder(
+        folder.to_string(),
+        client,
+        drive_id,
+        folder_id,
+        remote_path,
+        sync_password,
+        options,
+        false,
+    )
+    .await
+}
+
+/// 防止原路径为空(例如磁盘未挂载)或误删时, 清空云端的备份
+///
+/// 返回云端的文件数量, 没有安全检查时返回0
+async fn check_deletion_threshold(
+    source_path: &str,
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    sync_password: Option<Vec<u8>>,
+    options: &UpOptions,
+) -> anyhow::Result<u64> {
+    let Some(limit) = &options.deletion_limit else {
+        return Ok(0);
+    };
+    tracing::info!("检查云端删除数量 : {}", source_path);
+    let (deleted, total) = count_remote_deletions(
+        source_path.to_owned(),
+        client,
+        drive_id,
+        folder_id,
+        sync_password,
+        &options.filters,
+        true,
     )
     .await?;
+    check_source_not_empty(source_path, total).await?;
+    limit.check(deleted, total, source_path)?;
+    Ok(total)
+}
+
+async fn check_source_not_empty(source_path: &str, remote_total: u64) -> anyhow::Result<()> {
+    if remote_total > 0
+        && list_local_folder_file(&source_path.to_owned())
+            .await?
+            .is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "原路径为空, 可能是磁盘未挂载, 确认无误请使用 --force : {}",
+            source_path
+        ));
+    }
     Ok(())
 }
 
 #[async_recursion::async_recursion]
+#[tracing::instrument(skip_all, fields(path = %source_path, file_id = %folder_id))]
+#[allow(clippy::too_many_arguments)]
 async fn up_sync_folder(
     source_path: String,
     client: Arc<AdriveClient>,
     drive_id: String,
     folder_id: String,
+    remote_path: Vec<String>,
     sync_password: Option<Vec<u8>>,
+    options: Arc<UpOptions>,
+    recursive: bool,
 ) -> anyhow::Result<()> {
-    println!("向云端同步 : {}", source_path);
+    tracing::info!("向云端同步 : {}", source_path);
+    events::emit(
+        "scan-start",
+        json!({ "path": source_path, "remote_id": folder_id }),
+    );
     // 读取本地的文件
-    let metadata_list = list_local_folder_file(&source_path).await?;
+    let mut metadata_list = list_local_folder_file(&source_path).await?;
+    options.filters.retain_local(&mut metadata_list);
     // 读取远端文件
     let mut open_file_list =
         list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+    options
+        .filters
+        .retain_remote(&mut open_file_list, &sync_password);
     // 1. 删掉日期不一样的，名字不存在的
     // 整理一个本地留存的文件和修改日期的map
+    let local_index = index_local_folder(&metadata_list, &sync_password)?;
     let mut has_deleted = false;
-    let mut local_folder_list = Vec::new();
-    let mut local_file_update_map = HashMap::<String, (chrono::DateTime<Utc>, u64)>::new();
-    for (pb, m) in &metadata_list {
-        let name = pb
-            .file_name()
-            .with_context(|| "文件名为空(1)")?
-            .to_str()
-            .with_context(|| "文件名为空(2)")?
-            .to_string();
-        if name.is_empty() {
-            return Err(anyhow::anyhow!("文件名未空(3)"));
-        }
-        if m.is_file() {
-            let updated_at = chrono::DateTime::from(m.modified().unwrap());
-            let len = if let Some(_) = &sync_password {
-                let len = m.len();
-                (len / (1 << 20) * ((1 << 20) + 16)) + (len % (1 << 20) + 16)
-            } else {
-                m.len()
-            };
-            local_file_update_map.insert(name, (updated_at, len));
-        } else if m.is_dir() {
-            local_folder_list.push(name);
-        }
-    }
     for x in &open_file_list {
-        let mut delete = true;
-        let mut name = x.name.clone();
-        let mut error_file_name = false;
-        if let Some(sync_password) = &sync_password {
-            if let Ok(n) = decrypt_file_name(&name, sync_password) {
-                name = n;
-            } else {
-                println!(
-                    "删除云端文件 : {}/{}  (文件名解密失败)",
-                    source_path, x.name
-                );
-                error_file_name = true;
-            }
-        }
-        if !error_file_name {
-            match x.r#type {
-                AdriveOpenFileType::File => {
-                    if let Some((date, size)) = local_file_update_map.get(&name) {
-                        if *size as i64 == x.size && x.updated_at.timestamp() >= date.timestamp() {
-                            delete = false;
-                        } else {
-                            println!(
-                                "删除云端文件 : {}/{} (云端文件更新时间比本地更早)",
-                                source_path, name
-                            )
-                        }
-                    } else {
-                        println!(
-                            "删除云端文件 : {}/{} (本地对应文件已经删除)",
-                            source_path, name
-                        )
-                    }
-                }
-                Folder => {
-                    if local_folder_list.contains(&name) {
-                        delete = false;
-                    } else {
-                        println!("删除云端文件 : {}/{}/", source_path, name)
-                    }
-                }
+        let (name, state) = compare_remote_file(x, &local_index, &sync_password);
+        let (reason, reason_code) = match state {
+            RemoteFileState::Synced => continue,
+            RemoteFileState::Removed | RemoteFileState::Undecryptable if options.no_delete => {
+                options.report.add_skipped();
+                continue;
             }
-        }
-        if delete {
-            has_deleted = true;
-            delete_remote_file(Arc::clone(&client), x.drive_id.clone(), x.file_id.clone()).await?;
+            RemoteFileState::Outdated => ("云端文件更新时间比本地更早", "outdated"),
+            RemoteFileState::Removed => ("本地对应文件已经删除", "removed"),
+            RemoteFileState::Undecryptable => ("文件名解密失败", "undecryptable"),
+        };
+        has_deleted = true;
+        let result = if let Some(versions) = &options.versions {
+            tracing::info!("移入历史版本 : {}/{} ({})", source_path, name, reason);
+            versions.move_in(&remote_path, x).await
+        } else {
+            tracing::info!("删除云端文件 : {}/{} ({})", source_path, name, reason);
+            delete_remote_file(Arc::clone(&client), x.drive_id.clone(), x.file_id.clone()).await
+        };
+        let path = format!("{}/{}", source_path, name);
+        if options.report.check(&path, result)?.is_some() {
+            options.report.add_deleted();
+            events::emit(
+                "delete",
+                json!({
+                    "path": path,
+                    "remote_id": x.file_id,
+                    "location": "remote",
+                    "action": if options.versions.is_some() { "versions" } else { "trash" },
+                    "reason": reason_code,
+                }),
+            );
         }
     }
     if has_deleted {
         open_file_list =
             list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+        options
+            .filters
+            .retain_remote(&mut open_file_list, &sync_password);
     }
     // 上传不存在的
     let open_file_name_obj_map = open_file_list
@@ -227,9 +600,12 @@ async fn up_sync_folder(
         }
         if m.is_file() {
             if open_file_name_obj_map.contains_key(&remote_name) {
+                tracing::debug!("已同步, 跳过 : {:?}", pb);
+                options.report.add_skipped();
+                progress::skip_file(m.len());
                 continue;
             }
-            up_sync_file(
+            let result = up_sync_file(
                 pb.to_str()
                     .with_context(|| "file name is invalid")?
                     .to_string(),
@@ -239,39 +615,225 @@ async fn up_sync_folder(
                 folder_id.clone(),
                 remote_name,
                 sync_password.clone(),
+                options.bwlimit.as_deref(),
             )
-            .await?;
+            .await;
+            if let Some(bytes) = options.report.check(pb.display(), result)? {
+                options.report.add_transferred(bytes);
+            }
         } else if m.is_dir() {
-            let remote_dir_id = if let Some(obj) = open_file_name_obj_map.get(&remote_name) {
-                obj.file_id.clone()
+            let result = async {
+                let remote_dir_id = if let Some(obj) = open_file_name_obj_map.get(&remote_name) {
+                    if !recursive {
+                        return Ok(());
+                    }
+                    obj.file_id.clone()
+                } else {
+                    let file_id = client
+                        .adrive_open_file_create()
+                        .await
+                        .check_name_mode(CheckNameMode::Refuse)
+                        .drive_id(drive_id.as_str())
+                        .parent_file_id(folder_id.as_str())
+                        .name(remote_name.as_str())
+                        .r#type(AdriveOpenFileType::Folder)
+                        .retry_request()
+                        .await?
+                        .file_id;
+                    events::emit(
+                        "mkdir",
+                        json!({ "path": pb, "remote_id": file_id, "location": "remote" }),
+                    );
+                    file_id
+                };
+                up_sync_folder(
+                    pb.to_str()
+                        .with_context(|| "file name is invalid")?
+                        .to_string(),
+                    Arc::clone(&client),
+                    drive_id.clone(),
+                    remote_dir_id,
+                    [remote_path.as_slice(), &[remote_name]].concat(),
+                    sync_password.clone(),
+                    Arc::clone(&options),
+                    true,
+                )
+                .await
+            }
+            .await;
+            options.report.check(pb.display(), result)?;
+        }
+    }
+    Ok(())
+}
+
+/// 本地文件夹中的子文件夹, 以及文件的修改时间和(加密后的)大小
+struct LocalFolderIndex {
+    folders: Vec<String>,
+    files: HashMap<String, (chrono::DateTime<Utc>, u64)>,
+}
+
+fn index_local_folder(
+    metadata_list: &[(PathBuf, Metadata)],
+    sync_password: &Option<Vec<u8>>,
+) -> anyhow::Result<LocalFolderIndex> {
+    let mut folders = Vec::new();
+    let mut files = HashMap::new();
+    for (pb, m) in metadata_list {
+        let name = pb
+            .file_name()
+            .with_context(|| "文件名为空(1)")?
+            .to_str()
+            .with_context(|| "文件名为空(2)")?
+            .to_string();
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("文件名未空(3)"));
+        }
+        if m.is_file() {
+            let updated_at = chrono::DateTime::from(m.modified()?);
+            let len = if sync_password.is_some() {
+                let len = m.len();
+                (len / (1 << 20) * ((1 << 20) + 16)) + (len % (1 << 20) + 16)
             } else {
-                client
-                    .adrive_open_file_create()
-                    .await
-                    .check_name_mode(CheckNameMode::Refuse)
-                    .drive_id(drive_id.as_str())
-                    .parent_file_id(folder_id.as_str())
-                    .name(remote_name.as_str())
-                    .r#type(AdriveOpenFileType::Folder)
-                    .request()
-                    .await?
-                    .file_id
+                m.len()
             };
-            up_sync_folder(
-                pb.to_str()
-                    .with_context(|| "file name is invalid")?
-                    .to_string(),
-                Arc::clone(&client),
-                drive_id.clone(),
-                remote_dir_id,
-                sync_password.clone(),
-            )
-            .await?;
+            files.insert(name, (updated_at, len));
+        } else if m.is_dir() {
+            folders.push(name);
         }
     }
-    Ok(())
+    Ok(LocalFolderIndex { folders, files })
+}
+
+/// 云端文件与本地文件对比的结果
+enum RemoteFileState {
+    /// 与本地一致
+    Synced,
+    /// 本地文件已修改, 需要重新上传
+    Outdated,
+    /// 本地对应的文件已经删除
+    Removed,
+    /// 文件名无法用当前密码解密
+    Undecryptable,
 }
 
+/// 返回云端文件解密后的文件名以及对比结果
+fn compare_remote_file(
+    x: &AdriveOpenFile,
+    local_index: &LocalFolderIndex,
+    sync_password: &Option<Vec<u8>>,
+) -> (String, RemoteFileState) {
+    let mut name = x.name.clone();
+    if let Some(sync_password) = sync_password {
+        match decrypt_file_name(&name, sync_password) {
+            Ok(n) => name = n,
+            Err(_) => return (name, RemoteFileState::Undecryptable),
+        }
+    }
+    let state = match x.r#type {
+        AdriveOpenFileType::File => match local_index.files.get(&name) {
+            Some((date, size)) => {
+                if *size as i64 == x.size && x.updated_at.timestamp() >= date.timestamp() {
+                    RemoteFileState::Synced
+                } else {
+                    RemoteFileState::Outdated
+                }
+            }
+            None => RemoteFileState::Removed,
+        },
+        Folder => {
+            if local_index.folders.contains(&name) {
+                RemoteFileState::Synced
+            } else {
+                RemoteFileState::Removed
+            }
+        }
+    };
+    (name, state)
+}
+
+/// 同步前统计云端将被删除的文件数量(本地已不存在的, 不含需要重新上传的)以及云端文件总数
+///
+/// recursive 为 false 时不进入仍然存在的子文件夹
+#[async_recursion::async_recursion]
+async fn count_remote_deletions(
+    source_path: String,
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    sync_password: Option<Vec<u8>>,
+    filters: &Filters,
+    recursive: bool,
+) -> anyhow::Result<(u64, u64)> {
+    let mut metadata_list = list_local_folder_file(&source_path).await?;
+    filters.retain_local(&mut metadata_list);
+    let local_index = index_local_folder(&metadata_list, &sync_password)?;
+    let mut open_file_list =
+        list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+    filters.retain_remote(&mut open_file_list, &sync_password);
+    let mut deleted = 0;
+    let mut total = 0;
+    for x in &open_file_list {
+        let (name, state) = compare_remote_file(x, &local_index, &sync_password);
+        total += 1;
+        match state {
+            RemoteFileState::Removed | RemoteFileState::Undecryptable => {
+                deleted += 1;
+                if Folder.eq(&x.r#type) {
+                    let children = count_remote_files(
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        x.file_id.clone(),
+                    )
+                    .await?;
+                    deleted += children;
+                    total += children;
+                }
+            }
+            RemoteFileState::Synced if recursive && Folder.eq(&x.r#type) => {
+                let (d, t) = count_remote_deletions(
+                    Path::new(&source_path)
+                        .join(&name)
+                        .to_str()
+                        .with_context(|| "file name is invalid")?
+                        .to_string(),
+                    Arc::clone(&client),
+                    drive_id.clone(),
+                    x.file_id.clone(),
+                    sync_password.clone(),
+                    filters,
+                    true,
+                )
+                .await?;
+                deleted += d;
+                total += t;
+            }
+            _ => {}
+        }
+    }
+    Ok((deleted, total))
+}
+
+#[async_recursion::async_recursion]
+async fn count_remote_files(
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+) -> anyhow::Result<u64> {
+    let open_file_list =
+        list_remote_folder_file(&client, drive_id.clone(), folder_id.clone()).await?;
+    let mut count = open_file_list.len() as u64;
+    for x in &open_file_list {
+        if Folder.eq(&x.r#type) {
+            count += count_remote_files(Arc::clone(&client), drive_id.clone(), x.file_id.clone())
+                .await?;
+        }
+    }
+    Ok(count)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(path = %source_path, file_id = tracing::field::Empty))]
 async fn up_sync_file(
     source_path: String,
     m: &std::fs::Metadata,
@@ -280,8 +842,11 @@ async fn up_sync_file(
     folder_id: String,
     file_name: String,
     sync_password: Option<Vec<u8>>,
-) -> anyhow::Result<()> {
-    println!("上传至云端 : {}", source_path);
+    bwlimit: Option<&Limiter>,
+) -> anyhow::Result<u64> {
+    tracing::info!("上传至云端 : {}", source_path);
+    let _progress = progress::begin_file(&source_path, m.len());
+    events::transfer_start("upload", &source_path, m.len());
     let md = m
         .modified()
         .with_context(|| "modified is empty")?
@@ -298,7 +863,7 @@ async fn up_sync_file(
         .unwrap();
     let (sha1, size) = sum_file(source_path.as_str(), &sync_password).await?;
     let parts = vec![AdriveOpenFilePartInfoCreate { part_number: 1 }];
-    let result = client
+    let request = client
         .adrive_open_file_create()
         .await
         .check_name_mode(CheckNameMode::Refuse)
@@ -311,31 +876,86 @@ async fn up_sync_file(
         .content_hash(sha1)
         .local_modified_at(md)
         .local_created_at(cd)
-        .part_info_list(parts)
-        .request()
-        .await?;
+        .part_info_list(parts);
+    let result = create_remote_file(&client, &request).await?;
+    tracing::Span::current().record("file_id", result.file_id.as_str());
     if result.rapid_upload {
-        return Ok(());
+        tracing::debug!("秒传 : {}", source_path);
+        events::transfer_done(json!({
+            "path": source_path,
+            "remote_id": result.file_id,
+            "size": size,
+            "rapid": true,
+        }));
+        return Ok(0);
     }
     if result.exist {
         return Err(anyhow::anyhow!("文件已存在"));
     }
-    let url = result.part_info_list[0].upload_url.clone();
-    put_file(source_path.as_str(), &sync_password, url.as_str()).await?;
-    client
-        .adrive_open_file_complete()
-        .await
-        .drive_id(result.drive_id.as_str())
-        .file_id(result.file_id.as_str())
-        .upload_id(
-            result
-                .upload_id
-                .with_context(|| "upload_id is empty")?
-                .as_str(),
+    let upload_id = result.upload_id.with_context(|| "upload_id is empty")?;
+    let url = std::sync::Mutex::new(result.part_info_list[0].upload_url.clone());
+    // 每次重试都重新读取文件, 上传地址过期时重新获取
+    retry(|| async {
+        let current = url.lock().unwrap().clone();
+        match put_file(
+            source_path.as_str(),
+            &sync_password,
+            current.as_str(),
+            bwlimit,
         )
-        .request()
-        .await?;
-    Ok(())
+        .await
+        {
+            Err(err) if is_upload_url_expired(&err) => {
+                tracing::warn!("上传地址已过期, 重新获取 : {}", source_path);
+                let refreshed = client
+                    .adrive_open_file_get_upload_url()
+                    .await
+                    .drive_id(result.drive_id.as_str())
+                    .file_id(result.file_id.as_str())
+                    .upload_id(upload_id.as_str())
+                    .part_info_list(vec![AdriveOpenFilePartInfoCreate { part_number: 1 }])
+                    .retry_request()
+                    .await?
+                    .part_info_list
+                    .first()
+                    .with_context(|| "upload url is empty")?
+                    .upload_url
+                    .clone();
+                *url.lock().unwrap() = refreshed.clone();
+                put_file(
+                    source_path.as_str(),
+                    &sync_password,
+                    refreshed.as_str(),
+                    bwlimit,
+                )
+                .await
+            }
+            result => result,
+        }
+    })
+    .await?;
+    complete_remote_file(
+        &client,
+        &result.drive_id,
+        &result.file_id,
+        &upload_id,
+        size as i64,
+    )
+    .await?;
+    events::transfer_done(json!({
+        "path": source_path,
+        "remote_id": result.file_id,
+        "size": size,
+        "rapid": false,
+    }));
+    Ok(size)
+}
+
+/// 上传地址有效期较短, 过期后返回403
+fn is_upload_url_expired(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<HttpStatusError>())
+        .any(|err| err.status == reqwest::StatusCode::FORBIDDEN)
 }
 
 async fn sum_file(
@@ -359,7 +979,7 @@ async fn password_sha1(file_path: &str, password: &[u8]) -> anyhow::Result<(Stri
     let mut hasher = sha1::Sha1::new();
     let mut encryptor = encryptor_from_key(password)?;
     let mut reader = tokio::io::BufReader::new(file);
-    let mut buffer = [0u8; 1 << 20];
+    let mut buffer = vec![0u8; 1 << 20];
     let mut size = 0;
     let mut position = 0;
     loop {
@@ -407,47 +1027,65 @@ async fn put_file(
     file_path: &str,
     sync_password: &Option<Vec<u8>>,
     url: &str,
+    bwlimit: Option<&Limiter>,
 ) -> anyhow::Result<()> {
+    progress::restart_file();
     if let Some(sync_password) = sync_password {
-        put_file_with_password(file_path, sync_password, url).await
+        put_file_with_password(file_path, sync_password, url, bwlimit).await
     } else {
-        put_file_without_password(file_path, url).await
+        put_file_without_password(file_path, url, bwlimit).await
     }
 }
 
-async fn put_file_with_password(file_path: &str, password: &[u8], url: &str) -> anyhow::Result<()> {
+async fn put_file_with_password(
+    file_path: &str,
+    password: &[u8],
+    url: &str,
+    bwlimit: Option<&Limiter>,
+) -> anyhow::Result<()> {
     let (sender, body) = PutResource::channel_resource();
     let request = reqwest::Client::new().put(url).body(body).send();
     let cp = sender.clone();
     let read_file_back = async move {
-        let result = put_steam_with_password(cp, file_path, password).await;
-        if let Err(e) = result {
-            let _ = sender.send(Err(e)).await;
+        let result = put_steam_with_password(cp, file_path, password, bwlimit).await;
+        if let Err(e) = &result {
+            let _ = sender.send(Err(anyhow::anyhow!("{}", e))).await;
         }
+        result
     };
-    let (send, _read) = tokio::join!(request, read_file_back);
-    send?.error_for_status()?;
+    let (send, read) = tokio::join!(request, read_file_back);
+    // 读取文件失败时返回读取的错误, 而不是上传中断的错误
+    read.with_context(|| format!("读取文件失败: {}", file_path))?;
+    check_status(send?)?;
     Ok(())
 }
 
-async fn put_file_without_password(file_path: &str, url: &str) -> anyhow::Result<()> {
+async fn put_file_without_password(
+    file_path: &str,
+    url: &str,
+    bwlimit: Option<&Limiter>,
+) -> anyhow::Result<()> {
     let (sender, body) = PutResource::channel_resource();
     let request = reqwest::Client::new().put(url).body(body).send();
     let cp = sender.clone();
     let read_file_back = async move {
-        let result = put_steam(cp, file_path).await;
-        if let Err(e) = result {
-            let _ = sender.send(Err(e)).await;
+        let result = put_steam(cp, file_path, bwlimit).await;
+        if let Err(e) = &result {
+            let _ = sender.send(Err(anyhow::anyhow!("{}", e))).await;
         }
+        result
     };
-    let (send, _read) = tokio::join!(request, read_file_back);
-    send?.error_for_status()?;
+    let (send, read) = tokio::join!(request, read_file_back);
+    // 读取文件失败时返回读取的错误, 而不是上传中断的错误
+    read.with_context(|| format!("读取文件失败: {}", file_path))?;
+    check_status(send?)?;
     Ok(())
 }
 
 async fn put_steam(
     sender: tokio::sync::mpsc::Sender<anyhow::Result<Vec<u8>>>,
     path: &str,
+    bwlimit: Option<&Limiter>,
 ) -> anyhow::Result<()> {
     let mut buffer = vec![0u8; 1 << 10];
     let file = tokio::fs::File::open(path).await?;
@@ -457,7 +1095,9 @@ async fn put_steam(
         if n == 0 {
             break;
         }
+        bwlimit::acquire(bwlimit, n).await;
         sender.send(Ok(buffer[..n].to_vec())).await?;
+        progress::inc(n as u64);
     }
     Ok(())
 }
@@ -466,6 +1106,7 @@ async fn put_steam_with_password(
     sender: tokio::sync::mpsc::Sender<anyhow::Result<Vec<u8>>>,
     path: &str,
     password: &[u8],
+    bwlimit: Option<&Limiter>,
 ) -> anyhow::Result<()> {
     let mut buffer = vec![0u8; 1 << 20];
     let file = tokio::fs::File::open(path).await?;
@@ -475,6 +1116,8 @@ async fn put_steam_with_password(
     loop {
         let n = reader.read(&mut buffer[position..]).await?;
         position += n;
+        bwlimit::acquire(bwlimit, n).await;
+        progress::inc(n as u64);
         if n == 0 {
             let enc = encryptor.encrypt_last(&buffer[..position]);
             match enc {
@@ -507,40 +1150,12 @@ async fn put_steam_with_password(
 use reqwest::Body;
 use tokio::sync::mpsc::Sender;
 
-pub struct PutResource {
-    pub agent: Arc<reqwest::Client>,
-    pub url: String,
-    pub resource: Body,
-}
-
-impl PutResource {
-    pub async fn put(self) -> anyhow::Result<()> {
-        let text = self
-            .agent
-            .request(reqwest::Method::PUT, self.url.as_str())
-            .body(self.resource)
-            .send()
-            .await?
-            .text()
-            .await?;
-        println!("{}", text);
-        Ok(())
-    }
-}
+pub struct PutResource;
 
 impl PutResource {
-    pub async fn file_resource(path: &str) -> anyhow::Result<Body> {
-        let file = tokio::fs::read(path).await?;
-        Ok(Body::from(file))
-    }
-
     pub fn channel_resource() -> (Sender<anyhow::Result<Vec<u8>>>, Body) {
         let (sender, receiver) = tokio::sync::mpsc::channel::<anyhow::Result<Vec<u8>>>(16);
         let body = Body::wrap_stream(tokio_stream::wrappers::ReceiverStream::new(receiver));
         (sender, body)
     }
-
-    pub fn bytes_body(bytes: Vec<u8>) -> Body {
-        Body::from(bytes)
-    }
 }
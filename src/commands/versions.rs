@@ -0,0 +1,59 @@
+use crate::common::{open_remote_folder, parse_adrive_uri};
+use crate::config::{adrive_client_for_config, selected_account};
+use crate::versions::list_file_versions;
+use anyhow::Context;
+use chrono::Local;
+use clap::{arg, Command};
+
+pub const COMMAND_NAME: &str = "versions";
+
+pub fn command() -> Command {
+    Command::new(COMMAND_NAME).args(args())
+}
+
+fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(-s --source <SOURCE_PATH> "远端文件夹的地址, 例如 `adrive:///drive_id/file_path`"),
+        arg!(-p --password <PASSWORD> "云端加密密码").required(false),
+    ]
+}
+
+pub(crate) async fn run_sub_command(args: &clap::ArgMatches) -> anyhow::Result<()> {
+    let source: &String = args
+        .get_one("source")
+        .with_context(|| "source is required")?;
+    let (drive_id, folder_path) = parse_adrive_uri(source)?;
+    let client = adrive_client_for_config(selected_account(args)).await?;
+    let (folder_info, sync_password) = open_remote_folder(
+        &client,
+        drive_id.clone(),
+        folder_path,
+        args.get_one("password"),
+    )
+    .await?;
+    let mut versions = list_file_versions(&client, drive_id, folder_info.file_id, sync_password)
+        .await?
+        .into_iter()
+        .filter(|x| x.valid_until.is_some())
+        .collect::<Vec<_>>();
+    versions.sort_by(|a, b| (a.valid_until, &a.path).cmp(&(b.valid_until, &b.path)));
+    println!("替换/删除时间        上传时间             大小          文件");
+    for version in &versions {
+        println!(
+            "{}  {}  {:>12}  {}",
+            version
+                .valid_until
+                .unwrap()
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            version
+                .valid_from
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            version.file.size,
+            version.path,
+        );
+    }
+    println!("共 {} 个历史版本", versions.len());
+    Ok(())
+}
@@ -1,10 +1,11 @@
 use crate::custom_crypto::{
     decrypt_base64, decrypt_file_name, encrypt_buff_to_base64, encrypt_file_name,
 };
+use crate::retry::{check_status, is_not_found, retry, RetryRequest};
 use alipan::response::AdriveOpenFile;
 use alipan::{
-    AdriveAsyncTaskState, AdriveClient, AdriveOpenFilePartInfoCreate, AdriveOpenFileType,
-    CheckNameMode,
+    AdriveAsyncTaskState, AdriveClient, AdriveOpenFileCreate, AdriveOpenFileCreateRequest,
+    AdriveOpenFileGet, AdriveOpenFilePartInfoCreate, AdriveOpenFileType, CheckNameMode,
 };
 use anyhow::Context;
 use reqwest::Body;
@@ -26,7 +27,7 @@ pub async fn find_passbook_folder(
         .await
         .drive_id(drive_id.clone())
         .parent_file_id(folder_id.clone())
-        .request()
+        .retry_request()
         .await?;
     for x in list.items {
         if is_ignore_file(x.name.as_str()) {
@@ -45,7 +46,7 @@ pub async fn find_passbook_folder(
             .drive_id(drive_id.clone())
             .parent_file_id(folder_id.clone())
             .marker(list.next_marker.unwrap())
-            .request()
+            .retry_request()
             .await?;
         for x in list.items {
             if is_ignore_file(x.name.as_str()) {
@@ -72,7 +73,7 @@ pub async fn list_remote_folder_file(
         .await
         .drive_id(drive_id.clone())
         .parent_file_id(folder_id.clone())
-        .request()
+        .retry_request()
         .await?;
     for x in list.items {
         if is_ignore_file(x.name.as_str()) {
@@ -90,7 +91,7 @@ pub async fn list_remote_folder_file(
             .drive_id(drive_id.clone())
             .parent_file_id(folder_id.clone())
             .marker(list.next_marker.unwrap())
-            .request()
+            .retry_request()
             .await?;
         for x in list.items {
             if is_ignore_file(x.name.as_str()) {
@@ -126,6 +127,54 @@ pub async fn list_local_folder_file(
     Ok(metadata_list)
 }
 
+/// 递归统计本地文件夹中的文件数量和大小
+#[async_recursion::async_recursion]
+pub async fn count_local_files(source_path: String) -> anyhow::Result<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for (pb, m) in list_local_folder_file(&source_path).await? {
+        if m.is_file() {
+            files += 1;
+            bytes += m.len();
+        } else if m.is_dir() {
+            let (f, b) = count_local_files(
+                pb.to_str()
+                    .with_context(|| "file name is invalid")?
+                    .to_string(),
+            )
+            .await?;
+            files += f;
+            bytes += b;
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// 递归统计云端文件夹中的文件数量和大小
+#[async_recursion::async_recursion]
+pub async fn count_remote_files_size(
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+) -> anyhow::Result<(u64, u64)> {
+    let (mut files, mut bytes) = (0, 0);
+    for x in list_remote_folder_file(&client, drive_id.clone(), folder_id).await? {
+        match x.r#type {
+            AdriveOpenFileType::File => {
+                files += 1;
+                bytes += x.size as u64;
+            }
+            AdriveOpenFileType::Folder => {
+                let (f, b) =
+                    count_remote_files_size(Arc::clone(&client), drive_id.clone(), x.file_id)
+                        .await?;
+                files += f;
+                bytes += b;
+            }
+        }
+    }
+    Ok((files, bytes))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Passbook {
     pub key_encrypted: String,
@@ -142,24 +191,23 @@ pub async fn check_passbook_password(
         .await
         .file_id(passbook.file_id)
         .drive_id(passbook.drive_id)
-        .request()
+        .retry_request()
         .await?;
     let download_buff = download_file_to_buff(file_down_url.url).await?;
     let passbook: Passbook = toml::from_str(&download_buff)?;
     let test_decrypted = decrypt_file_name(&passbook.test_encrypted, password.as_bytes())?;
     if test_decrypted.eq("test") {
-        return Ok(decrypt_base64(
-            &passbook.key_encrypted,
-            password.as_bytes(),
-        )?);
+        return decrypt_base64(&passbook.key_encrypted, password.as_bytes());
     }
-    return Err(anyhow::anyhow!("密码不正确"));
+    Err(anyhow::anyhow!("密码不正确"))
 }
 
 pub async fn download_file_to_buff(url: String) -> anyhow::Result<String> {
-    let resp = ::reqwest::get(url).await?;
-    let resp = resp.text().await?;
-    Ok(resp)
+    retry(|| async {
+        let resp = check_status(::reqwest::get(url.as_str()).await?)?;
+        Ok::<_, anyhow::Error>(resp.text().await?)
+    })
+    .await
 }
 
 pub async fn create_passbook_password(
@@ -171,11 +219,11 @@ pub async fn create_passbook_password(
     let key = random_string(64);
     let passbook = Passbook {
         key_encrypted: encrypt_buff_to_base64(key.as_slice(), password.as_bytes())?,
-        test_encrypted: encrypt_file_name("test", &password.as_bytes())?,
+        test_encrypted: encrypt_file_name("test", password.as_bytes())?,
     };
     let passbook = toml::to_string(&passbook)?;
     let parts = vec![AdriveOpenFilePartInfoCreate { part_number: 1 }];
-    let file = client
+    let request = client
         .adrive_open_file_create()
         .await
         .check_name_mode(CheckNameMode::Refuse)
@@ -184,36 +232,42 @@ pub async fn create_passbook_password(
         .r#type(AdriveOpenFileType::File)
         .name("passbook")
         .size(passbook.len() as i64)
-        .part_info_list(parts)
-        .request()
-        .await?;
-    reqwest::Client::new()
-        .put(file.part_info_list[0].upload_url.as_str())
-        .body(Body::from(passbook))
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-    client
-        .adrive_open_file_complete()
-        .await
-        .drive_id(file.drive_id.clone())
-        .file_id(file.file_id.clone())
-        .upload_id(file.upload_id.clone())
-        .request()
-        .await?;
+        .part_info_list(parts);
+    let file = create_remote_file(client, &request).await?;
+    if file.exist {
+        return Err(anyhow::anyhow!("passbook 已存在"));
+    }
+    let upload_id = file
+        .upload_id
+        .clone()
+        .with_context(|| "upload_id is empty")?;
+    retry(|| async {
+        let resp = reqwest::Client::new()
+            .put(file.part_info_list[0].upload_url.as_str())
+            .body(Body::from(passbook.clone()))
+            .send()
+            .await?;
+        Ok::<_, anyhow::Error>(check_status(resp)?.text().await?)
+    })
+    .await?;
+    complete_remote_file(
+        client,
+        &file.drive_id,
+        &file.file_id,
+        &upload_id,
+        passbook.len() as i64,
+    )
+    .await?;
     Ok(key)
 }
 
 fn random_string(len: usize) -> Vec<u8> {
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
-    let buff = thread_rng()
+    thread_rng()
         .sample_iter(&Alphanumeric)
         .take(len)
-        .collect::<Vec<u8>>();
-    buff
+        .collect::<Vec<u8>>()
 }
 
 pub async fn delete_remote_file(
@@ -221,14 +275,147 @@ pub async fn delete_remote_file(
     drive_id: String,
     file_id: String,
 ) -> anyhow::Result<()> {
-    let result = client
+    let request = client
         .adrive_open_file_recyclebin_trash()
         .await
         .drive_id(drive_id)
+        .file_id(file_id);
+    let mut attempts = 0;
+    let result = retry(|| {
+        attempts += 1;
+        request.request()
+    })
+    .await;
+    let result = match result {
+        // 重试时文件已经不存在, 上一次请求已经生效
+        Err(err) if attempts > 1 && is_not_found(&err) => return Ok(()),
+        result => result?,
+    };
+    wait_async_task(&client, result.async_task_id, "文件删除失败").await
+}
+
+/// 移动云端文件到另一个文件夹, 目标文件夹中有同名文件时报错
+pub async fn move_remote_file(
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    file_id: String,
+    to_parent_file_id: String,
+) -> anyhow::Result<()> {
+    let request = client
+        .adrive_open_file_move()
+        .await
+        .drive_id(drive_id.clone())
+        .file_id(file_id.clone())
+        .to_parent_file_id(to_parent_file_id.clone())
+        .check_name_mode(CheckNameMode::Refuse);
+    let mut attempts = 0;
+    let result = retry(|| {
+        attempts += 1;
+        request.request()
+    })
+    .await;
+    let retried = attempts > 1;
+    let result = match result {
+        Err(err) if retried && is_not_found(&err) => return Ok(()),
+        result => result?,
+    };
+    if result.exist {
+        // 重试时同名文件可能就是上一次请求已经移动过去的这个文件
+        if retried
+            && get_remote_file(&client, drive_id, file_id)
+                .await?
+                .parent_file_id
+                == to_parent_file_id
+        {
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!("文件移动失败, 目标文件夹中已存在同名文件"));
+    }
+    wait_async_task(&client, result.async_task_id, "文件移动失败").await
+}
+
+/// 上传文件前创建云端文件(Refuse), 创建不是幂等的, 上一次请求可能已经在服务端生效:
+/// 重试后同名文件已存在, 并且是在第一次请求之后创建的, 说明是上一次请求创建的, 放入回收站后重新创建
+pub async fn create_remote_file(
+    client: &Arc<AdriveClient>,
+    request: &AdriveOpenFileCreateRequest,
+) -> anyhow::Result<AdriveOpenFileCreate> {
+    // 允许本地和服务端的时间有一些误差
+    let started = chrono::Utc::now() - chrono::Duration::minutes(1);
+    let mut attempts = 0;
+    let result = retry(|| {
+        attempts += 1;
+        request.request()
+    })
+    .await?;
+    if !result.exist || attempts == 1 || result.file_id.is_empty() {
+        return Ok(result);
+    }
+    let exists = get_remote_file(client, result.drive_id.clone(), result.file_id.clone()).await?;
+    if exists.created_at < started {
+        return Ok(result);
+    }
+    tracing::warn!(
+        "重试前的请求已经创建了文件, 放入回收站后重新创建 : {}",
+        exists.name
+    );
+    delete_remote_file(Arc::clone(client), exists.drive_id, exists.file_id).await?;
+    retry(|| request.request()).await
+}
+
+/// 完成上传, 上一次请求可能已经在服务端生效, 重试时上传已经不存在并且云端文件的大小一致则视为成功
+pub async fn complete_remote_file(
+    client: &Arc<AdriveClient>,
+    drive_id: &str,
+    file_id: &str,
+    upload_id: &str,
+    size: i64,
+) -> anyhow::Result<()> {
+    let request = client
+        .adrive_open_file_complete()
+        .await
+        .drive_id(drive_id)
         .file_id(file_id)
-        .request()
-        .await?;
-    if let Some(task) = result.async_task_id {
+        .upload_id(upload_id);
+    let mut attempts = 0;
+    let err = match retry(|| {
+        attempts += 1;
+        request.request()
+    })
+    .await
+    {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    if attempts > 1 && is_not_found(&err) {
+        let file = get_remote_file(client, drive_id.to_string(), file_id.to_string()).await;
+        if file.is_ok_and(|file| file.size == size) {
+            return Ok(());
+        }
+    }
+    Err(err)
+}
+
+async fn get_remote_file(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    file_id: String,
+) -> anyhow::Result<AdriveOpenFileGet> {
+    client
+        .adrive_open_file_get()
+        .await
+        .drive_id(drive_id)
+        .file_id(file_id)
+        .retry_request()
+        .await
+}
+
+async fn wait_async_task(
+    client: &Arc<AdriveClient>,
+    task: Option<String>,
+    failed_message: &str,
+) -> anyhow::Result<()> {
+    if let Some(task) = task {
         if !task.is_empty() {
             let mut state = AdriveAsyncTaskState::Running;
             while state == AdriveAsyncTaskState::Running {
@@ -237,12 +424,12 @@ pub async fn delete_remote_file(
                     .adrive_open_file_async_task_get()
                     .await
                     .async_task_id(task.as_str())
-                    .request()
+                    .retry_request()
                     .await?
                     .state;
                 match state {
                     AdriveAsyncTaskState::Failed => {
-                        return Err(anyhow::anyhow!("文件删除失败"));
+                        return Err(anyhow::anyhow!("{}", failed_message));
                     }
                     AdriveAsyncTaskState::Succeed => {}
                     AdriveAsyncTaskState::Running => {}
@@ -253,7 +440,112 @@ pub async fn delete_remote_file(
     Ok(())
 }
 
-fn is_ignore_file(file_name: &str) -> bool {
+/// 按名称查找云端文件夹中的文件, 包括以`.`开头的文件
+pub async fn find_remote_file(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    name: &str,
+) -> anyhow::Result<Option<AdriveOpenFile>> {
+    let mut marker: Option<String> = None;
+    loop {
+        let list = client
+            .adrive_open_file_list()
+            .await
+            .drive_id(drive_id.clone())
+            .parent_file_id(folder_id.clone())
+            .marker(marker)
+            .retry_request()
+            .await?;
+        if let Some(x) = list.items.into_iter().find(|x| x.name.eq(name)) {
+            return Ok(Some(x));
+        }
+        if list.next_marker.is_none() {
+            return Ok(None);
+        }
+        marker = list.next_marker;
+    }
+}
+
+/// 创建云端文件夹, 已存在时返回已有文件夹的id
+pub async fn create_remote_folder(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    name: &str,
+) -> anyhow::Result<String> {
+    Ok(client
+        .adrive_open_file_create()
+        .await
+        .check_name_mode(CheckNameMode::Refuse)
+        .drive_id(drive_id)
+        .parent_file_id(folder_id)
+        .name(name)
+        .r#type(AdriveOpenFileType::Folder)
+        .retry_request()
+        .await?
+        .file_id)
+}
+
+/// 解析 `adrive:///{DriveID}/{文件夹路径}`, 返回 drive_id 和解码后的文件夹路径
+pub fn parse_adrive_uri(uri: &str) -> anyhow::Result<(String, String)> {
+    let url = url::Url::parse(uri).with_context(|| format!("url is invalid: {}", uri))?;
+    if !"adrive".eq(url.scheme()) {
+        return Err(anyhow::anyhow!("云端路径必须是adrive协议"));
+    }
+    let sp = url.path().split('/').collect::<Vec<&str>>();
+    if sp.len() < 3 || !sp[0].is_empty() {
+        return Err(anyhow::anyhow!(
+            "云端路径必须是 `adrive:///{{DriveID}}/{{文件夹路径}}`"
+        ));
+    }
+    let drive_id = sp[1].to_owned();
+    let folder_path = "/".to_owned() + &sp[2..].join("/");
+    let folder_path = urlencoding::decode(&folder_path)?.to_string();
+    Ok((drive_id, folder_path))
+}
+
+/// 获取云端文件夹, 并校验密码。云端有passbook时必须提供密码
+pub async fn open_remote_folder(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    folder_path: String,
+    password: Option<&String>,
+) -> anyhow::Result<(AdriveOpenFileGet, Option<Vec<u8>>)> {
+    let folder_info = get_remote_folder(client, drive_id.clone(), folder_path).await?;
+    let (passbook, _other_files) =
+        find_passbook_folder(client, drive_id, folder_info.file_id.clone()).await?;
+    let sync_password = match (passbook, password) {
+        (Some(passbook), Some(password)) => {
+            Some(check_passbook_password(Arc::clone(client), passbook, password.clone()).await?)
+        }
+        (Some(_), None) => return Err(anyhow::anyhow!("需要密码")),
+        (None, Some(_)) => return Err(anyhow::anyhow!("云端无密码")),
+        (None, None) => None,
+    };
+    Ok((folder_info, sync_password))
+}
+
+/// 获取云端文件夹, 不检查密码
+pub async fn get_remote_folder(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    folder_path: String,
+) -> anyhow::Result<AdriveOpenFileGet> {
+    let folder_info = client
+        .adrive_open_file_get_by_path()
+        .await
+        .drive_id(drive_id)
+        .file_path(folder_path)
+        .retry_request()
+        .await?;
+    if !AdriveOpenFileType::Folder.eq(&folder_info.r#type) {
+        return Err(anyhow::anyhow!("云端路径必须是一个文件夹"));
+    }
+    Ok(folder_info)
+}
+
+pub fn is_ignore_file(file_name: &str) -> bool {
     file_name.starts_with('.')
         || file_name.eq("System Volume Information")
         || file_name.eq("RECYCLE.BIN")
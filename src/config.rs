@@ -1,167 +1,802 @@
 use crate::config;
-use alipan::{
-    AccessToken, AdriveClient, OAuthClient, OAuthClientAccessTokenManager,
-    OAuthClientAccessTokenStore,
-};
+use crate::migrate;
+use crate::oauth::refresh_access_token;
+use crate::retry::{HttpStatusError, RetryRequest};
+use crate::secrets::{self, EncryptionConfig};
+use alipan::oauth_users_info::OauthUsersInfo;
+use alipan::{AccessToken, AccessTokenLoader, AdriveClient};
 use anyhow::Context;
 use async_trait::async_trait;
 use once_cell::sync::OnceCell;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, RwLock};
 
+/// 顶层的 `app` 和 `access_token` 为默认账户, `accounts` 中为使用 `--account` 选择的其他账户
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// 配置文件的版本, 加载旧版本的配置文件时自动升级
+    #[serde(default)]
+    pub version: u32,
+    /// 存在时app密钥、token和任务的密码在配置文件中加密保存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
     pub app: AppConfig,
     pub access_token: AccessToken,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub accounts: BTreeMap<String, AccountConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<JobConfig>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    #[serde(default)]
+    pub app: AppConfig,
+    #[serde(default)]
+    pub access_token: AccessToken,
+}
+
+impl Config {
+    /// 账户的app配置, account 为 None 时为默认账户
+    pub fn app(&self, account: Option<&str>) -> Option<&AppConfig> {
+        match account {
+            None => Some(&self.app),
+            Some(name) => self.accounts.get(name).map(|a| &a.app),
+        }
+    }
+
+    pub fn access_token(&self, account: Option<&str>) -> Option<&AccessToken> {
+        match account {
+            None => Some(&self.access_token),
+            Some(name) => self.accounts.get(name).map(|a| &a.access_token),
+        }
+    }
+
+    fn set_app(&mut self, account: Option<&str>, app: AppConfig) {
+        match account {
+            None => self.app = app,
+            Some(name) => self.accounts.entry(name.to_string()).or_default().app = app,
+        }
+    }
+
+    fn set_access_token(&mut self, account: Option<&str>, access_token: AccessToken) {
+        match account {
+            None => self.access_token = access_token,
+            Some(name) => {
+                self.accounts
+                    .entry(name.to_string())
+                    .or_default()
+                    .access_token = access_token
+            }
+        }
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+fn redact(value: &mut String) {
+    if !value.is_empty() {
+        *value = REDACTED.to_string();
+    }
+}
+
+impl Config {
+    /// 所有账户, 默认账户的名称为 None
+    pub fn all_accounts(&self) -> impl Iterator<Item = (Option<&str>, &AppConfig, &AccessToken)> {
+        std::iter::once((None, &self.app, &self.access_token)).chain(
+            self.accounts
+                .iter()
+                .map(|(name, a)| (Some(name.as_str()), &a.app, &a.access_token)),
+        )
+    }
+
+    /// app密钥、token和任务的密码
+    fn secret_fields_mut(&mut self) -> Vec<&mut String> {
+        let mut fields = vec![];
+        let accounts = self
+            .accounts
+            .values_mut()
+            .map(|a| (&mut a.app, &mut a.access_token));
+        for (app, token) in std::iter::once((&mut self.app, &mut self.access_token)).chain(accounts)
+        {
+            fields.push(&mut app.client_secret);
+            fields.push(&mut token.access_token);
+            fields.push(&mut token.refresh_token);
+        }
+        for job in &mut self.jobs {
+            if let Some(password) = &mut job.password {
+                fields.push(password);
+            }
+        }
+        fields
+    }
+
+    /// 隐藏app密钥、token和任务的密码, 用于显示
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        for value in config.secret_fields_mut() {
+            redact(value);
+        }
+        config
+    }
+
+    /// 加密保存时, 加密所有不为空的密钥
+    fn encrypted(&self) -> anyhow::Result<Config> {
+        let mut config = self.clone();
+        if config.encryption.is_some() {
+            for value in config.secret_fields_mut() {
+                if !value.is_empty() {
+                    *value = secrets::encrypt(value)?;
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// 解密加载的配置文件, 手动填写的未加密的值保持不变, 下次保存时加密
+    fn decrypt(&mut self) -> anyhow::Result<()> {
+        let Some(encryption) = &self.encryption else {
+            return Ok(());
+        };
+        secrets::unlock(encryption)?;
+        for value in self.secret_fields_mut() {
+            if secrets::is_encrypted(value) {
+                *value = secrets::decrypt(value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn account_not_found(account: Option<&str>) -> anyhow::Error {
+    anyhow::anyhow!("配置文件中没有这个账户 : {}", account.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AppConfig {
     pub client_id: String,
+    /// 为空时使用 PKCE 授权和刷新token
+    #[serde(default)]
     pub client_secret: String,
 }
 
+/// 请求失败时的重试策略
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub rate_limit_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60000,
+            rate_limit_backoff_ms: 10000,
+        }
+    }
+}
+
+/// 同步任务, 由 `daemon` 按计划运行, 或者使用 `run <name>` 手动运行
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    pub name: String,
+    pub direction: JobDirection,
+    /// `up` 为本地文件夹的URI, `down` 为云端文件夹的URI
+    pub source: String,
+    /// `up` 为云端文件夹的URI, `down` 为本地文件夹的URI
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// 从环境变量读取密码
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+    /// 从文件读取密码, 去掉首尾空白
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+    /// `30m` 每隔一段时间运行, 或者 `03:00 15:00` 每天在指定的时间运行, 不设置时只能手动运行
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    /// 使用 `accounts` 中的账户, 不设置时使用默认账户
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// 传给 `up` / `down` 的其他参数, 例如 `["--keep-going", "--versions"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobDirection {
+    Up,
+    Down,
+}
+
+/// 当前的配置文件版本, 修改配置文件的结构时增加, 并在 `migrate` 中添加迁移
+pub const CONFIG_VERSION: u32 = 1;
+pub const CONFIG_ENV: &str = "ARSYNC_CONFIG";
+/// 账户的环境变量, 见 `account_env`
+const CLIENT_ID_ENV: &str = "CLIENT_ID";
+const CLIENT_SECRET_ENV: &str = "CLIENT_SECRET";
+const ACCESS_TOKEN_ENV: &str = "ACCESS_TOKEN";
+const REFRESH_TOKEN_ENV: &str = "REFRESH_TOKEN";
+
 static CONFIG_PATH_CELL: OnceCell<String> = OnceCell::new();
 static CONFIG_CELL: OnceCell<RwLock<Config>> = OnceCell::new();
+/// 使用环境变量中的 refresh_token 刷新得到的token, 只保存在内存中
+static ENV_TOKENS: Mutex<BTreeMap<Option<String>, AccessToken>> = Mutex::const_new(BTreeMap::new());
 
+/// 先写入临时文件再重命名, 写入中断或者其他进程同时读取时不会读到不完整的配置文件
 pub async fn save_config() -> anyhow::Result<()> {
     let config = CONFIG_CELL
         .get()
         .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?;
     let config = config.read().await;
-    let config = toml::to_string(&*config)?;
-    tokio::fs::write(
-        CONFIG_PATH_CELL
-            .get()
-            .with_context(|| "config cell not set")?
-            .as_str(),
-        config,
-    )
-    .await?;
+    let config = toml::to_string(&config.encrypted()?)?;
+    let path = get_path()?;
+    let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+    if let Err(err) = write_new_file(&tmp_path, config.as_bytes()).await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(err).with_context(|| format!("写入配置文件失败 : {}", tmp_path));
+    }
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .with_context(|| format!("写入配置文件失败 : {}", path))?;
+    Ok(())
+}
+
+async fn write_new_file(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // 配置文件中有密钥和token, 只有自己可以读写
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// 锁定 `{配置文件}.lock`, 其他进程持有锁时等待, 返回的文件关闭时解锁
+///
+/// 修改配置文件的进程依次 读取-修改-写入, 不会覆盖其他进程的修改
+async fn lock_config() -> anyhow::Result<File> {
+    let lock_path = format!("{}.lock", get_path()?);
+    tokio::task::spawn_blocking(move || {
+        let lock =
+            File::create(&lock_path).with_context(|| format!("创建锁文件失败 : {}", lock_path))?;
+        lock.lock()
+            .with_context(|| format!("锁定配置文件失败 : {}", lock_path))?;
+        Ok(lock)
+    })
+    .await?
+}
+
+/// 配置文件可以被其他用户读取时警告
+#[cfg(unix)]
+async fn check_permissions(path: &str) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = tokio::fs::metadata(path).await?.permissions().mode();
+    if mode & 0o077 != 0 {
+        tracing::warn!(
+            "配置文件可以被其他用户读取, 建议运行 `chmod 600 {}` : 当前权限 {:o}",
+            path,
+            mode & 0o777
+        );
+    }
     Ok(())
 }
 
+/// 读取配置文件, 旧版本的配置文件在内存中升级, 同时返回升级前的版本
+async fn read_config_file(path: &str) -> anyhow::Result<(Config, u32)> {
+    let text = tokio::fs::read_to_string(path).await?;
+    let mut table: toml::Table = toml::from_str(&text)?;
+    let from = migrate::migrate(&mut table)?;
+    // 没有升级时解析原文, 错误信息中的行号与配置文件一致
+    let text = if from == CONFIG_VERSION {
+        text
+    } else {
+        toml::to_string(&table)?
+    };
+    let mut config: Config = toml::from_str(&text)?;
+    config.decrypt()?;
+    Ok((config, from))
+}
+
 pub async fn load_config() -> anyhow::Result<()> {
-    let config = tokio::fs::read_to_string(
-        CONFIG_PATH_CELL
-            .get()
-            .with_context(|| "config cell not set")?
-            .as_str(),
-    )
-    .await?;
-    let config: Config = toml::from_str(&config)?;
+    let path = get_path()?;
+    let (config, from) = read_config_file(path).await?;
+    #[cfg(unix)]
+    check_permissions(path).await?;
     CONFIG_CELL
         .set(RwLock::new(config))
         .map_err(|_| anyhow::anyhow!("配置文件重复加载"))?;
+    if from < CONFIG_VERSION {
+        save_migrated(path, from).await?;
+    }
+    Ok(())
+}
+
+/// 保存升级后的配置文件, 先把原文件备份为 `{配置文件}.v{原版本}.{时间}.bak`
+async fn save_migrated(path: &str, from: u32) -> anyhow::Result<()> {
+    let lock = lock_config().await?;
+    let backup = format!(
+        "{}.v{}.{}.bak",
+        path,
+        from,
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    );
+    tokio::fs::copy(path, &backup)
+        .await
+        .with_context(|| format!("备份配置文件失败 : {}", backup))?;
+    save_config().await?;
+    drop(lock);
+    tracing::info!(
+        "配置文件已从版本 {} 升级到版本 {}, 原文件备份为 : {}",
+        from,
+        CONFIG_VERSION,
+        backup
+    );
+    Ok(())
+}
+
+/// 重新读取配置文件, 得到其他进程的修改(例如刷新的token), 需要先锁定配置文件
+async fn reload_config() -> anyhow::Result<()> {
+    let (config, _) = read_config_file(get_path()?).await?;
+    *CONFIG_CELL
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?
+        .write()
+        .await = config;
+    Ok(())
+}
+
+/// 修改内存中的配置并保存, 需要先锁定配置文件
+async fn modify_config(modify: impl FnOnce(&mut Config)) -> anyhow::Result<()> {
+    let mut config = CONFIG_CELL
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?
+        .write()
+        .await;
+    modify(&mut config);
+    drop(config);
+    save_config().await
+}
+
+/// 锁定配置文件, 重新读取后修改并保存
+async fn update_config(modify: impl FnOnce(&mut Config)) -> anyhow::Result<()> {
+    let lock = lock_config().await?;
+    reload_config().await?;
+    modify_config(modify).await?;
+    drop(lock);
     Ok(())
 }
 
 pub async fn new_config() -> anyhow::Result<()> {
-    let config = Config::default();
+    let config = Config {
+        version: CONFIG_VERSION,
+        ..Default::default()
+    };
     CONFIG_CELL
         .set(RwLock::new(config.clone()))
         .map_err(|_| anyhow::anyhow!("配置文件重复加载"))?;
     Ok(())
 }
 
+/// 配置文件的内容, 加上环境变量的覆盖
 pub async fn get_config() -> anyhow::Result<Config> {
-    Ok(CONFIG_CELL
+    let mut config = CONFIG_CELL
         .get()
         .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?
         .read()
         .await
-        .clone())
+        .clone();
+    apply_env_overrides(&mut config)?;
+    Ok(config)
 }
 
-pub async fn set_app_config(app_config: AppConfig) -> anyhow::Result<()> {
-    let mut config = CONFIG_CELL
-        .get()
-        .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?
-        .write()
-        .await;
-    config.app = app_config;
-    drop(config);
-    save_config().await?;
+/// 账户的环境变量, 默认账户为 `ARSYNC_{name}`,
+/// 其他账户为 `ARSYNC_ACCOUNT_{账户}_{name}`, 账户名转为大写, 字母和数字以外的字符替换为`_`
+pub fn account_env(account: Option<&str>, name: &str) -> String {
+    match account {
+        None => format!("ARSYNC_{}", name),
+        Some(account) => {
+            let account = account
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>();
+            format!("ARSYNC_ACCOUNT_{}_{}", account, name)
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> anyhow::Result<Option<T>> {
+    env_var(name)
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|_| anyhow::anyhow!("环境变量 {} 必须是数字 : {}", name, v))
+        })
+        .transpose()
+}
+
+/// 环境变量覆盖配置文件中各个账户的app配置和重试策略, 只在内存中生效, 不写入配置文件
+///
+/// token 的环境变量见 `env_access_token`
+fn apply_env_overrides(config: &mut Config) -> anyhow::Result<()> {
+    let names = std::iter::once(None)
+        .chain(config.accounts.keys().cloned().map(Some))
+        .collect::<Vec<_>>();
+    for name in names {
+        let account = name.as_deref();
+        let app = match account {
+            None => &mut config.app,
+            Some(name) => &mut config.accounts.get_mut(name).unwrap().app,
+        };
+        if let Some(client_id) = env_var(&account_env(account, CLIENT_ID_ENV)) {
+            app.client_id = client_id;
+        }
+        if let Some(client_secret) = env_var(&account_env(account, CLIENT_SECRET_ENV)) {
+            app.client_secret = client_secret;
+        }
+    }
+    let retry = &mut config.retry;
+    if let Some(value) = env_number("ARSYNC_RETRY_MAX_RETRIES")? {
+        retry.max_retries = value;
+    }
+    if let Some(value) = env_number("ARSYNC_RETRY_INITIAL_BACKOFF_MS")? {
+        retry.initial_backoff_ms = value;
+    }
+    if let Some(value) = env_number("ARSYNC_RETRY_MAX_BACKOFF_MS")? {
+        retry.max_backoff_ms = value;
+    }
+    if let Some(value) = env_number("ARSYNC_RETRY_RATE_LIMIT_BACKOFF_MS")? {
+        retry.rate_limit_backoff_ms = value;
+    }
     Ok(())
 }
 
-pub async fn get_app_config() -> anyhow::Result<AppConfig> {
-    Ok(get_config().await?.app)
+/// 环境变量中的token, 存在时代替配置文件中的token, 不会写入配置文件
+///
+/// 只有 access_token 时直接使用, 不会刷新; 有 refresh_token 时过期前刷新, 刷新后的token只保存在内存中
+fn env_access_token(account: Option<&str>) -> Option<AccessToken> {
+    let access_token = env_var(&account_env(account, ACCESS_TOKEN_ENV));
+    let refresh_token = env_var(&account_env(account, REFRESH_TOKEN_ENV));
+    if access_token.is_none() && refresh_token.is_none() {
+        return None;
+    }
+    Some(AccessToken {
+        token_type: "Bearer".to_string(),
+        // 没有 access_token 时立即刷新
+        expires_in: if access_token.is_some() { 7200 } else { 0 },
+        access_token: access_token.unwrap_or_default(),
+        refresh_token: refresh_token.unwrap_or_default(),
+        created_at: chrono::Utc::now().timestamp(),
+    })
+}
+
+/// 设置账户的app配置, 账户不存在时新建
+pub async fn set_app_config(account: Option<&str>, app_config: AppConfig) -> anyhow::Result<()> {
+    update_config(|config| config.set_app(account, app_config)).await
+}
+
+/// 设置配置文件的加密方式, None 为不加密
+pub async fn set_encryption(encryption: Option<EncryptionConfig>) -> anyhow::Result<()> {
+    update_config(|config| config.encryption = encryption).await
+}
+
+pub async fn get_app_config(account: Option<&str>) -> anyhow::Result<AppConfig> {
+    get_config()
+        .await?
+        .app(account)
+        .cloned()
+        .ok_or_else(|| account_not_found(account))
+}
+
+pub async fn get_retry_config() -> RetryConfig {
+    get_config()
+        .await
+        .map(|config| config.retry)
+        .unwrap_or_default()
+}
+
+pub fn get_path() -> anyhow::Result<&'static str> {
+    Ok(CONFIG_PATH_CELL
+        .get()
+        .with_context(|| "config cell not set")?
+        .as_str())
+}
+
+pub async fn get_jobs() -> anyhow::Result<Vec<JobConfig>> {
+    Ok(get_config().await?.jobs)
 }
 
-pub async fn set_path(config_path: &str) -> anyhow::Result<()> {
+/// 没有使用 `-c` 时的配置文件
+///
+/// 依次使用环境变量 `ARSYNC_CONFIG`, `$XDG_CONFIG_HOME/arsync/config.toml`, `~/.config/arsync/config.toml`
+pub fn default_path() -> anyhow::Result<String> {
+    let non_empty = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    if let Some(path) = non_empty(CONFIG_ENV) {
+        return Ok(path);
+    }
+    let config_home = match non_empty("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            non_empty("HOME").with_context(|| "没有设置 HOME, 请使用 `-c` 指定配置文件")?,
+        )
+        .join(".config"),
+    };
+    Ok(config_home
+        .join("arsync")
+        .join("config.toml")
+        .to_string_lossy()
+        .to_string())
+}
+
+/// 配置文件存在时加载, 不能解析时返回错误, 不会覆盖已有的配置文件
+///
+/// 不存在时只有 create 为 true (`config` 和 `login`)才新建, 否则返回错误, 避免 `-c` 写错路径时静默使用空的配置
+pub async fn set_path(config_path: &str, create: bool) -> anyhow::Result<()> {
     CONFIG_PATH_CELL
         .set(config_path.to_string())
         .map_err(|_| anyhow::anyhow!("配置路径重复设置"))?;
 
-    let data = tokio::fs::metadata(config_path).await.ok();
-    if let Some(_data) = data {
-        match config::load_config().await {
-            Err(_) => {
-                config::new_config().await?;
-                config::save_config().await?;
-            }
-            _ => {}
-        };
+    if tokio::fs::try_exists(config_path).await? {
+        config::load_config()
+            .await
+            .with_context(|| format!("加载配置文件失败 : {}", config_path))?;
+    } else if !create {
+        return Err(anyhow::anyhow!(
+            "配置文件不存在 : {}, 请检查 `-c` 或者 `ARSYNC_CONFIG`, 或者先运行 `arsync config` 或 `arsync login` 新建",
+            config_path
+        ));
     } else {
+        if let Some(parent) = Path::new(config_path)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("创建配置文件夹失败 : {}", parent.display()))?;
+        }
         config::new_config().await?;
         config::save_config().await?;
+        tracing::info!("新建配置文件 : {}", config_path);
     }
     Ok(())
 }
 
-pub async fn set_access_token(access_token: AccessToken) -> anyhow::Result<()> {
-    let mut config = CONFIG_CELL
-        .get()
-        .ok_or_else(|| anyhow::anyhow!("配置文件未加载"))?
-        .write()
-        .await;
-    config.access_token = access_token;
-    drop(config);
-    save_config().await?;
-    Ok(())
+/// 设置账户的token, 账户不存在时新建
+pub async fn set_access_token(
+    account: Option<&str>,
+    access_token: AccessToken,
+) -> anyhow::Result<()> {
+    update_config(|config| config.set_access_token(account, access_token)).await
 }
 
-pub async fn get_access_token() -> anyhow::Result<AccessToken> {
-    Ok(get_config().await?.access_token)
+/// 账户的token, 设置了token的环境变量时为环境变量中的(或者由它刷新得到的)token
+pub async fn get_access_token(account: Option<&str>) -> anyhow::Result<AccessToken> {
+    if let Some(token) = env_access_token(account) {
+        let refreshed = ENV_TOKENS
+            .lock()
+            .await
+            .get(&account.map(str::to_string))
+            .cloned();
+        return Ok(refreshed.unwrap_or(token));
+    }
+    get_config()
+        .await?
+        .access_token(account)
+        .cloned()
+        .ok_or_else(|| account_not_found(account))
 }
 
+/// 从配置文件中读取一个账户的token, 快过期时刷新并写回同一个账户
+///
+/// 没有 client_secret 时(PKCE)刷新token不发送 client_secret
 #[derive(Debug)]
-pub struct ConfigAccessTokenStore();
+pub struct ConfigAccessTokenLoader {
+    account: Option<String>,
+    /// 并发的请求只刷新一次
+    refresh_lock: Mutex<()>,
+}
+
+impl ConfigAccessTokenLoader {
+    pub fn new(account: Option<&str>) -> Self {
+        ConfigAccessTokenLoader {
+            account: account.map(str::to_string),
+            refresh_lock: Mutex::new(()),
+        }
+    }
+}
+
+fn is_fresh(token: &AccessToken) -> bool {
+    chrono::Utc::now().timestamp() - token.created_at < token.expires_in * 3 / 4
+}
 
-impl ConfigAccessTokenStore {
-    pub fn new() -> Self {
-        ConfigAccessTokenStore()
+/// 重新授权的命令, 用于错误提示
+pub fn login_hint(account: Option<&str>) -> String {
+    match account {
+        None => "`arsync login` 或者 `arsync config`".to_string(),
+        Some(name) => format!(
+            "`arsync --account {0} login` 或者 `arsync --account {0} config`",
+            name
+        ),
     }
 }
 
+/// 刷新token的请求被拒绝(不是网络问题或者限流), refresh_token 已经过期或者被撤销
+fn is_refresh_rejected(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<HttpStatusError>())
+        .any(|err| {
+            err.status.is_client_error() && err.status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
 #[async_trait]
-impl OAuthClientAccessTokenStore for ConfigAccessTokenStore {
-    async fn get_access_token(&self) -> anyhow::Result<Option<AccessToken>> {
-        Ok(Some(get_access_token().await?))
+impl AccessTokenLoader for ConfigAccessTokenLoader {
+    async fn get_access_token(&self) -> anyhow::Result<AccessToken> {
+        let account = self.account.as_deref();
+        if let Some(env_token) = env_access_token(account) {
+            return self.get_env_access_token(env_token).await;
+        }
+        let token = get_access_token(account).await?;
+        if is_fresh(&token) {
+            return Ok(token);
+        }
+        let _guard = self.refresh_lock.lock().await;
+        // 等待锁的时候其他请求可能已经刷新
+        let token = get_access_token(account).await?;
+        if is_fresh(&token) {
+            return Ok(token);
+        }
+        // 其他进程可能已经刷新, 刷新后旧的 refresh_token 会失效, 锁定配置文件后使用文件中最新的token
+        let lock = lock_config().await?;
+        reload_config().await?;
+        let token = get_access_token(account).await?;
+        if is_fresh(&token) {
+            return Ok(token);
+        }
+        if token.refresh_token.is_empty() {
+            return Err(anyhow::anyhow!(
+                "没有登录, 请运行 {} 授权",
+                login_hint(account)
+            ));
+        }
+        let token = refresh(
+            account,
+            &token.refresh_token,
+            format!("请运行 {} 重新登录", login_hint(account)),
+        )
+        .await?;
+        modify_config(|config| config.set_access_token(account, token.clone())).await?;
+        drop(lock);
+        Ok(token)
+    }
+}
+
+impl ConfigAccessTokenLoader {
+    /// 使用环境变量中的token, 不读取也不修改配置文件, 同一个进程中的所有请求共享刷新后的token
+    async fn get_env_access_token(&self, env_token: AccessToken) -> anyhow::Result<AccessToken> {
+        if env_token.refresh_token.is_empty() {
+            return Ok(env_token);
+        }
+        let account = self.account.as_deref();
+        let mut tokens = ENV_TOKENS.lock().await;
+        // 第一次使用时开始计算 access_token 的过期时间
+        let token = tokens
+            .entry(self.account.clone())
+            .or_insert(env_token)
+            .clone();
+        if is_fresh(&token) {
+            return Ok(token);
+        }
+        let token = refresh(
+            account,
+            &token.refresh_token,
+            format!("请更新环境变量 {}", account_env(account, REFRESH_TOKEN_ENV)),
+        )
+        .await?;
+        tokens.insert(self.account.clone(), token.clone());
+        Ok(token)
     }
+}
 
-    async fn set_access_token(&self, access_token: AccessToken) -> anyhow::Result<()> {
-        set_access_token(access_token).await
+/// 刷新token, refresh_token 被拒绝时提示 hint
+async fn refresh(
+    account: Option<&str>,
+    refresh_token: &str,
+    hint: String,
+) -> anyhow::Result<AccessToken> {
+    let app = get_app_config(account).await?;
+    match refresh_access_token(&app, refresh_token).await {
+        Ok(token) => Ok(token),
+        Err(err) if is_refresh_rejected(&err) => {
+            Err(err.context(format!("refresh_token 已经过期或者被撤销, {}", hint)))
+        }
+        Err(err) => Err(err.context("刷新token失败, 请检查网络后重试")),
     }
 }
 
-pub async fn adrive_client_for_config() -> anyhow::Result<Arc<AdriveClient>> {
-    let app_config = get_app_config().await?;
+/// 请求账户的用户信息, 检查token是否可用, 快过期时会先刷新
+///
+/// 在开始同步前调用, 需要重新登录时直接提示, 不会在同步中途才失败
+pub async fn check_access_token(
+    client: &AdriveClient,
+    account: Option<&str>,
+) -> anyhow::Result<OauthUsersInfo> {
+    with_account(account, client.oauth_users_info().await.retry_request()).await
+}
+
+tokio::task_local! {
+    /// 正在运行的命令使用的账户, 授权失效时用于提示重新登录的命令
+    static CURRENT_ACCOUNT: Option<String>;
+}
+
+/// 在 `account` 下运行, 期间请求的授权失效时提示这个账户重新登录
+pub async fn with_account<F: Future>(account: Option<&str>, f: F) -> F::Output {
+    CURRENT_ACCOUNT.scope(account.map(str::to_string), f).await
+}
+
+/// 当前账户重新授权的命令
+pub fn current_login_hint() -> String {
+    CURRENT_ACCOUNT
+        .try_with(|account| login_hint(account.as_deref()))
+        .unwrap_or_else(|_| login_hint(None))
+}
+
+/// `--account` 选择的账户, 没有指定时为 None(默认账户)
+pub fn selected_account(args: &clap::ArgMatches) -> Option<&str> {
+    args.get_one::<String>("account").map(String::as_str)
+}
+
+/// 创建账户的客户端并检查token, 需要重新登录时直接返回错误
+pub async fn adrive_client_for_config(account: Option<&str>) -> anyhow::Result<Arc<AdriveClient>> {
+    let client = adrive_client(account).await?;
+    check_access_token(&client, account).await?;
+    Ok(client)
+}
+
+/// 创建账户的客户端, 不检查token
+pub async fn adrive_client(account: Option<&str>) -> anyhow::Result<Arc<AdriveClient>> {
+    let app_config = get_app_config(account).await?;
+    if app_config.client_id.is_empty() {
+        return Err(anyhow::anyhow!(
+            "没有配置app的 client_id, 请先使用 `config` 或者 `config set-app` 配置"
+        ));
+    }
     let client = AdriveClient::default()
         .set_client_id(app_config.client_id.clone())
         .await
-        .set_access_token_loader(Box::new(OAuthClientAccessTokenManager {
-            oauth_client: Arc::new(
-                OAuthClient::default()
-                    .set_client_id(app_config.client_id.clone())
-                    .await
-                    .set_client_secret(app_config.client_secret.clone())
-                    .await,
-            ),
-            access_token_store: Arc::new(Box::new(ConfigAccessTokenStore {})),
-        }))
+        .set_access_token_loader(Box::new(ConfigAccessTokenLoader::new(account)))
         .await;
     Ok(client.into())
 }
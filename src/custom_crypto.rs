@@ -5,9 +5,10 @@ use aes_gcm::aes::Aes256;
 use aes_gcm::{Aes256Gcm, AesGcm};
 use base64::Engine;
 
-pub fn encryptor_from_key(
-    password: &[u8],
-) -> anyhow::Result<Encryptor<AesGcm<Aes256, U12>, StreamBE32<AesGcm<Aes256, U12>>>> {
+pub type StreamEncryptor = Encryptor<AesGcm<Aes256, U12>, StreamBE32<AesGcm<Aes256, U12>>>;
+pub type StreamDecryptor = Decryptor<AesGcm<Aes256, U12>, StreamBE32<AesGcm<Aes256, U12>>>;
+
+pub fn encryptor_from_key(password: &[u8]) -> anyhow::Result<StreamEncryptor> {
     let key_bytes = md5::compute(password).0;
     let key_hex = hex::encode(key_bytes);
     let key = key_hex.as_bytes();
@@ -32,12 +33,10 @@ pub fn encrypt_buff_to_base64(buff: &[u8], password: &[u8]) -> anyhow::Result<St
 }
 
 pub fn encrypt_file_name(file_name: &str, password: &[u8]) -> anyhow::Result<String> {
-    Ok(encrypt_buff_to_base64(file_name.as_bytes(), password)?)
+    encrypt_buff_to_base64(file_name.as_bytes(), password)
 }
 
-pub fn decryptor_from_key(
-    password: &[u8],
-) -> anyhow::Result<Decryptor<AesGcm<Aes256, U12>, StreamBE32<AesGcm<Aes256, U12>>>> {
+pub fn decryptor_from_key(password: &[u8]) -> anyhow::Result<StreamDecryptor> {
     let key_bytes = md5::compute(password).0;
     let key_hex = hex::encode(key_bytes);
     let key = key_hex.as_bytes();
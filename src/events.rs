@@ -0,0 +1,131 @@
+use chrono::Local;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 事件格式的版本, 字段只增加不修改, 修改时增加版本号
+pub const EVENTS_VERSION: u32 = 1;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `--events jsonl` 时每个动作输出一行json到标准输出, 日志改为输出到标准错误
+struct Events {
+    /// 同步的本地根目录, 事件中的路径都是相对于这个目录的
+    root: String,
+    current: Mutex<Option<Transfer>>,
+}
+
+/// 正在上传或下载的文件
+struct Transfer {
+    kind: &'static str,
+    path: String,
+    size: u64,
+    bytes: u64,
+    last_emit: Instant,
+}
+
+static EVENTS_CELL: OnceCell<Events> = OnceCell::new();
+
+pub fn enable(root: &str) {
+    let _ = EVENTS_CELL.set(Events {
+        root: root.trim_end_matches('/').to_string(),
+        current: Mutex::new(None),
+    });
+}
+
+pub fn enabled() -> bool {
+    EVENTS_CELL.get().is_some()
+}
+
+/// 本地路径转换为相对于同步根目录的路径
+fn relative(events: &Events, path: &str) -> String {
+    Path::new(path)
+        .strip_prefix(&events.root)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
+
+/// 输出一个事件, fields 中的 `path` 会被转换为相对路径
+pub fn emit(event: &str, fields: impl Serialize) {
+    let Some(events) = EVENTS_CELL.get() else {
+        return;
+    };
+    let mut value = match serde_json::to_value(fields) {
+        Ok(serde_json::Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    if let Some(serde_json::Value::String(path)) = value.get("path") {
+        let path = relative(events, path);
+        value.insert("path".to_string(), path.into());
+    }
+    value.insert("event".to_string(), event.into());
+    value.insert("version".to_string(), EVENTS_VERSION.into());
+    value.insert("time".to_string(), Local::now().to_rfc3339().into());
+    if let Ok(line) = serde_json::to_string(&value) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+    }
+}
+
+/// 开始上传(kind=`upload`)或下载(kind=`download`)一个文件, 输出 `{kind}-start`
+pub fn transfer_start(kind: &'static str, path: &str, size: u64) {
+    let Some(events) = EVENTS_CELL.get() else {
+        return;
+    };
+    emit(
+        &format!("{}-start", kind),
+        serde_json::json!({ "path": path, "size": size }),
+    );
+    *events.current.lock().unwrap() = Some(Transfer {
+        kind,
+        path: path.to_string(),
+        size,
+        bytes: 0,
+        last_emit: Instant::now(),
+    });
+}
+
+/// 当前文件重试, 从头开始计算传输的字节数
+pub fn transfer_restart() {
+    if let Some(events) = EVENTS_CELL.get() {
+        if let Some(transfer) = events.current.lock().unwrap().as_mut() {
+            transfer.bytes = 0;
+        }
+    }
+}
+
+/// 当前文件传输了 bytes 字节, 每秒最多输出一次 `{kind}-progress`
+pub fn transfer_bytes(bytes: u64) {
+    let Some(events) = EVENTS_CELL.get() else {
+        return;
+    };
+    let mut current = events.current.lock().unwrap();
+    let Some(transfer) = current.as_mut() else {
+        return;
+    };
+    transfer.bytes += bytes;
+    if transfer.last_emit.elapsed() < PROGRESS_INTERVAL {
+        return;
+    }
+    transfer.last_emit = Instant::now();
+    let event = format!("{}-progress", transfer.kind);
+    let fields = serde_json::json!({
+        "path": transfer.path,
+        "bytes": transfer.bytes,
+        "size": transfer.size,
+    });
+    drop(current);
+    emit(&event, fields);
+}
+
+/// 当前文件传输完成, 输出 `{kind}-done`
+pub fn transfer_done(fields: serde_json::Value) {
+    let Some(events) = EVENTS_CELL.get() else {
+        return;
+    };
+    if let Some(transfer) = events.current.lock().unwrap().take() {
+        emit(&format!("{}-done", transfer.kind), fields);
+    }
+}
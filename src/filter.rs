@@ -0,0 +1,121 @@
+use crate::custom_crypto::decrypt_file_name;
+use alipan::response::AdriveOpenFile;
+use clap::{arg, ArgAction};
+use std::fs::Metadata;
+use std::path::PathBuf;
+
+/// `--exclude` 按文件名排除文件和文件夹, 支持 `*` 和 `?` 通配符
+///
+/// 被排除的文件既不会同步, 也不会因为另一端不存在而被删除
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    exclude: Vec<Vec<char>>,
+}
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--exclude <PATTERN> "排除文件名匹配的文件和文件夹, 支持 `*` 和 `?`, 可以指定多次")
+            .required(false)
+            .action(ArgAction::Append),
+    ]
+}
+
+impl Filters {
+    pub fn from_args(args: &clap::ArgMatches) -> Self {
+        Filters {
+            exclude: args
+                .get_many::<String>("exclude")
+                .into_iter()
+                .flatten()
+                .map(|p| p.chars().collect())
+                .collect(),
+        }
+    }
+
+    pub fn is_excluded(&self, name: &str) -> bool {
+        if self.exclude.is_empty() {
+            return false;
+        }
+        let name: Vec<char> = name.chars().collect();
+        self.exclude
+            .iter()
+            .any(|pattern| wildcard_match(pattern, &name))
+    }
+
+    /// 去掉被排除的本地文件
+    pub fn retain_local(&self, metadata_list: &mut Vec<(PathBuf, Metadata)>) {
+        metadata_list.retain(|(pb, _)| {
+            !pb.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| self.is_excluded(name))
+        });
+    }
+
+    /// 去掉被排除的云端文件, 加密的文件名先解密再匹配, 解密失败的不排除
+    pub fn retain_remote(
+        &self,
+        open_file_list: &mut Vec<AdriveOpenFile>,
+        sync_password: &Option<Vec<u8>>,
+    ) {
+        if self.exclude.is_empty() {
+            return;
+        }
+        open_file_list.retain(|x| {
+            let name = match sync_password {
+                Some(password) => match decrypt_file_name(&x.name, password) {
+                    Ok(name) => name,
+                    Err(_) => return true,
+                },
+                None => x.name.clone(),
+            };
+            !self.is_excluded(&name)
+        });
+    }
+}
+
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(patterns: &[&str]) -> Filters {
+        Filters {
+            exclude: patterns.iter().map(|p| p.chars().collect()).collect(),
+        }
+    }
+
+    #[test]
+    fn star_matches_any_suffix() {
+        let filters = filters(&["*.tmp"]);
+        assert!(filters.is_excluded("a.tmp"));
+        assert!(filters.is_excluded(".tmp"));
+        assert!(!filters.is_excluded("a.tmp.txt"));
+        assert!(!filters.is_excluded("a.txt"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_char() {
+        let filters = filters(&["file?.log"]);
+        assert!(filters.is_excluded("file1.log"));
+        assert!(filters.is_excluded("file文.log"));
+        assert!(!filters.is_excluded("file.log"));
+        assert!(!filters.is_excluded("file12.log"));
+    }
+
+    #[test]
+    fn any_pattern_excludes() {
+        let filters = filters(&["node_modules", "~*"]);
+        assert!(filters.is_excluded("node_modules"));
+        assert!(filters.is_excluded("~lock.docx"));
+        assert!(!filters.is_excluded("node_modules2"));
+        assert!(!Filters::default().is_excluded("a.tmp"));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::{self, JobConfig, JobDirection};
+use crate::{arsync, commands};
+use anyhow::Context;
+use chrono::{Local, NaiveTime};
+use std::collections::HashSet;
+use std::fs::{File, TryLockError};
+use std::time::{Duration, Instant};
+
+/// 任务的运行计划
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// `30m` 每隔一段时间运行, 守护进程启动后立即运行一次
+    Every(Duration),
+    /// `03:00 15:00` 每天在指定的本地时间运行
+    Daily(Vec<NaiveTime>),
+}
+
+impl Schedule {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        if value.contains(':') {
+            let mut times = value
+                .split_whitespace()
+                .map(|time| {
+                    NaiveTime::parse_from_str(time, "%H:%M")
+                        .with_context(|| format!("时间格式不正确: {}", time))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            times.sort();
+            return Ok(Schedule::Daily(times));
+        }
+        let unit_start = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let number = value[..unit_start]
+            .parse::<u64>()
+            .with_context(|| format!("计划格式不正确: {}", value))?;
+        let unit = match &value[unit_start..] {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(anyhow::anyhow!("计划格式不正确: {}", value)),
+        };
+        if number == 0 {
+            return Err(anyhow::anyhow!("计划间隔不能为0: {}", value));
+        }
+        Ok(Schedule::Every(Duration::from_secs(number * unit)))
+    }
+
+    /// 距离下一次运行的时间, last 为上一次开始运行的时间
+    fn delay(&self, last: Option<Instant>) -> Duration {
+        match self {
+            Schedule::Every(interval) => last.map_or(Duration::ZERO, |last| {
+                interval.saturating_sub(last.elapsed())
+            }),
+            Schedule::Daily(times) => {
+                let now = Local::now().naive_local();
+                let next = times
+                    .iter()
+                    .map(|time| now.date().and_time(*time))
+                    .find(|next| *next > now)
+                    .unwrap_or_else(|| (now.date() + chrono::Days::new(1)).and_time(times[0]));
+                (next - now).to_std().unwrap_or_default()
+            }
+        }
+    }
+}
+
+/// 检查任务名称不重复, 计划的格式正确
+pub fn validate_jobs(jobs: &[JobConfig]) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    for job in jobs {
+        if job.name.is_empty()
+            || !job
+                .name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow::anyhow!(
+                "任务名称只能包含字母、数字、`-` 和 `_` : {:?}",
+                job.name
+            ));
+        }
+        if !names.insert(job.name.as_str()) {
+            return Err(anyhow::anyhow!("任务名称重复 : {}", job.name));
+        }
+        if let Some(schedule) = &job.schedule {
+            Schedule::parse(schedule).with_context(|| format!("任务 {} 的计划不正确", job.name))?;
+        }
+        // 事件输出会改变整个进程的标准输出和日志, 守护进程中的任务不能各自设置
+        if job
+            .options
+            .iter()
+            .any(|x| x == "--events" || x.starts_with("--events="))
+        {
+            return Err(anyhow::anyhow!(
+                "任务 {} 的 options 不能使用 `--events`",
+                job.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn find_job(name: &str) -> anyhow::Result<JobConfig> {
+    let jobs = config::get_jobs().await?;
+    validate_jobs(&jobs)?;
+    jobs.into_iter()
+        .find(|job| job.name == name)
+        .with_context(|| format!("配置文件中没有这个任务 : {}", name))
+}
+
+fn job_password(job: &JobConfig) -> anyhow::Result<Option<String>> {
+    if let Some(password) = &job.password {
+        return Ok(Some(password.clone()));
+    }
+    if let Some(name) = &job.password_env {
+        let password = std::env::var(name).with_context(|| format!("环境变量未设置 : {}", name))?;
+        return Ok(Some(password));
+    }
+    if let Some(path) = &job.password_file {
+        let password = std::fs::read_to_string(path)
+            .with_context(|| format!("读取密码文件失败 : {}", path))?;
+        return Ok(Some(password.trim().to_string()));
+    }
+    Ok(None)
+}
+
+/// 任务转换为 `arsync up` / `arsync down` 的命令行参数, 使用 `--name=value` 以免值以 `-` 开头
+fn job_args(job: &JobConfig) -> anyhow::Result<Vec<String>> {
+    let command = match job.direction {
+        JobDirection::Up => commands::up::COMMAND_NAME,
+        JobDirection::Down => commands::down::COMMAND_NAME,
+    };
+    let mut argv = vec!["arsync".to_string()];
+    if let Some(account) = &job.account {
+        argv.push(format!("--account={}", account));
+    }
+    argv.extend([
+        command.to_string(),
+        format!("--source={}", job.source),
+        format!("--target={}", job.target),
+        "--no-progress".to_string(),
+    ]);
+    if let Some(password) = job_password(job)? {
+        argv.push(format!("--password={}", password));
+    }
+    for pattern in &job.exclude {
+        argv.push(format!("--exclude={}", pattern));
+    }
+    argv.extend(job.options.iter().cloned());
+    Ok(argv)
+}
+
+/// 运行一个任务, 同一个任务正在运行时(包括其他进程中的)不运行并返回 `Ok(false)`
+///
+/// 使用配置文件旁边的 `{配置文件}.{任务名称}.lock` 加锁, 进程退出时锁自动释放
+pub async fn run_job(job: &JobConfig) -> anyhow::Result<bool> {
+    let lock_path = format!("{}.{}.lock", config::get_path()?, job.name);
+    let lock =
+        File::create(&lock_path).with_context(|| format!("创建锁文件失败 : {}", lock_path))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Ok(false),
+        Err(TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("锁定任务失败 : {}", lock_path))
+        }
+    }
+    let matches = arsync::command()
+        .try_get_matches_from(job_args(job)?)
+        .with_context(|| format!("任务 {} 的参数不正确", job.name))?;
+    let result = config::with_account(job.account.as_deref(), async {
+        match matches.subcommand() {
+            Some((commands::up::COMMAND_NAME, args)) => commands::up::run_sub_command(args).await,
+            Some((commands::down::COMMAND_NAME, args)) => {
+                commands::down::run_sub_command(args).await
+            }
+            _ => unreachable!(),
+        }
+    })
+    .await;
+    drop(lock);
+    result.map(|_| true)
+}
+
+/// 按计划反复运行一个任务, 上一次运行结束后才会计算下一次运行的时间, 同一个任务不会重叠运行
+pub async fn run_on_schedule(job: JobConfig, schedule: Schedule) {
+    let mut last = None;
+    loop {
+        tokio::time::sleep(schedule.delay(last)).await;
+        last = Some(Instant::now());
+        tracing::info!("开始运行任务 : {}", job.name);
+        match run_job(&job).await {
+            Ok(true) => tracing::info!("任务完成 : {}", job.name),
+            Ok(false) => tracing::warn!("任务正在其他进程中运行, 跳过本次运行 : {}", job.name),
+            Err(err) => tracing::error!("任务失败 : {} : {:#}", job.name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn every(value: &str) -> Option<Duration> {
+        match Schedule::parse(value).unwrap() {
+            Schedule::Every(interval) => Some(interval),
+            Schedule::Daily(_) => None,
+        }
+    }
+
+    #[test]
+    fn parse_interval() {
+        assert_eq!(every("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(every("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(every("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(every("90"), Some(Duration::from_secs(90)));
+        assert_eq!(every(" 45s "), Some(Duration::from_secs(45)));
+        assert!(Schedule::parse("0s").is_err());
+        assert!(Schedule::parse("10x").is_err());
+        assert!(Schedule::parse("m").is_err());
+        assert!(Schedule::parse("").is_err());
+    }
+
+    #[test]
+    fn parse_daily_times() {
+        match Schedule::parse("15:00 03:00").unwrap() {
+            Schedule::Daily(times) => assert_eq!(times, vec![time(3, 0), time(15, 0)]),
+            schedule => panic!("unexpected schedule: {:?}", schedule),
+        }
+        assert!(Schedule::parse("03:00 25:00").is_err());
+        assert!(Schedule::parse("3:00pm").is_err());
+    }
+}
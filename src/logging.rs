@@ -0,0 +1,84 @@
+use crate::progress::{console_ansi, Console};
+use anyhow::Context;
+use clap::{arg, ArgAction};
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(-v --verbose "输出更详细的日志, `-vv` 输出全部日志")
+            .action(ArgAction::Count)
+            .global(true),
+        arg!(-q --quiet "只输出警告和错误, `-qq` 只输出错误")
+            .action(ArgAction::Count)
+            .global(true),
+        arg!(--"log-file" <LOG_FILE> "日志同时追加写入到文件")
+            .required(false)
+            .global(true),
+        arg!(--"log-format" <FORMAT> "日志格式, `text`(默认) 或者 `json`")
+            .required(false)
+            .value_parser(["text", "json"])
+            .global(true),
+    ]
+}
+
+/// 根据命令行参数初始化日志, 设置了 `RUST_LOG` 时以 `RUST_LOG` 为准
+pub fn init(matches: &clap::ArgMatches) -> anyhow::Result<()> {
+    let level = match matches.get_count("verbose") as i32 - matches.get_count("quiet") as i32 {
+        i32::MIN..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let json = matches
+        .get_one::<String>("log-format")
+        .is_some_and(|v| v == "json");
+    let filter = || {
+        EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new(format!("arsync={}", level)))
+    };
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![];
+    layers.push(if json {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_ansi(console_ansi())
+            .with_writer(Console)
+            .with_filter(filter())
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(console_ansi())
+            .with_target(false)
+            .with_writer(Console)
+            .with_filter(filter())
+            .boxed()
+    });
+    if let Some(log_file) = matches.get_one::<String>("log-file") {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .with_context(|| format!("打开日志文件失败 : {}", log_file))?;
+        let writer = Mutex::new(file);
+        layers.push(if json {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_filter(filter())
+                .boxed()
+        } else {
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_target(false)
+                .with_writer(writer)
+                .with_filter(filter())
+                .boxed()
+        });
+    }
+    tracing_subscriber::registry().with(layers).try_init()?;
+    Ok(())
+}
@@ -1,10 +1,32 @@
 mod arsync;
+mod bwlimit;
 mod commands;
 mod common;
 mod config;
 mod custom_crypto;
+mod events;
+mod filter;
+mod jobs;
+mod logging;
+mod migrate;
+mod oauth;
+mod progress;
+mod quarantine;
+mod report;
+mod retry;
+mod secrets;
+mod versions;
+#[cfg(target_os = "linux")]
+mod watch;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    arsync::run_command().await
+    let result = arsync::run_command().await;
+    if let Err(err) = &result {
+        if err.downcast_ref::<report::PartialFailure>().is_some() {
+            eprintln!("Error: {}", err);
+            std::process::exit(report::PARTIAL_FAILURE_EXIT_CODE);
+        }
+    }
+    result
 }
@@ -0,0 +1,49 @@
+use crate::config::{AppConfig, CONFIG_VERSION};
+use alipan::AccessToken;
+use anyhow::Context;
+use toml::{Table, Value};
+
+/// 从版本 N 升级到 N+1 的迁移, 下标为 N
+const MIGRATIONS: [fn(&mut Table) -> anyhow::Result<()>; CONFIG_VERSION as usize] = [migrate_v0];
+
+/// 配置文件的版本, 没有 `version` 时为0
+fn version(config: &Table) -> anyhow::Result<u32> {
+    match config.get("version") {
+        None => Ok(0),
+        Some(Value::Integer(version)) => u32::try_from(*version)
+            .with_context(|| format!("配置项 `version` 不正确 : {}", version)),
+        Some(value) => Err(anyhow::anyhow!("配置项 `version` 必须是整数 : {}", value)),
+    }
+}
+
+/// 把旧版本的配置文件升级到当前版本, 返回升级前的版本
+pub fn migrate(config: &mut Table) -> anyhow::Result<u32> {
+    let from = version(config)?;
+    if from > CONFIG_VERSION {
+        return Err(anyhow::anyhow!(
+            "配置文件的版本 {} 比当前程序支持的版本 {} 新, 请升级arsync",
+            from,
+            CONFIG_VERSION
+        ));
+    }
+    for version in from..CONFIG_VERSION {
+        MIGRATIONS[version as usize](config)
+            .with_context(|| format!("配置文件从版本 {} 升级失败", version))?;
+        config.insert("version".to_string(), Value::Integer(version as i64 + 1));
+    }
+    Ok(from)
+}
+
+/// 版本0: 没有 `version`, 只有 `app` 和 `access_token`, 手动编写的配置文件在登录前可能没有 `access_token`
+fn migrate_v0(config: &mut Table) -> anyhow::Result<()> {
+    if !config.contains_key("app") {
+        config.insert("app".to_string(), Value::try_from(AppConfig::default())?);
+    }
+    if !config.contains_key("access_token") {
+        config.insert(
+            "access_token".to_string(),
+            Value::try_from(AccessToken::default())?,
+        );
+    }
+    Ok(())
+}
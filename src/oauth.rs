@@ -0,0 +1,151 @@
+use crate::config::AppConfig;
+use crate::retry::{check_status, retry};
+use alipan::oauth_access_token::OauthAccessToken;
+use alipan::AccessToken;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+const API_HOST: &str = "https://openapi.alipan.com";
+pub const DEFAULT_SCOPE: &str = "user:base,file:all:read,file:all:write,album:shared:read";
+
+/// PKCE (RFC 7636), 授权时发送 `code_challenge`, 换取token时发送 `code_verifier`, 不需要 client_secret
+#[derive(Debug, Clone)]
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let verifier = URL_SAFE_NO_PAD.encode(bytes);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Pkce {
+            verifier,
+            challenge,
+        }
+    }
+
+    /// 在授权地址上附加 `code_challenge`
+    pub fn authorize_url(&self, url: &str) -> anyhow::Result<String> {
+        let mut url = url::Url::parse(url)?;
+        url.query_pairs_mut()
+            .append_pair("code_challenge", &self.challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.to_string())
+    }
+}
+
+/// 授权时的 `state`, 回调时校验, 防止CSRF
+pub fn random_state() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// 扫码登录的二维码, 用阿里云盘App扫描 `authorize_url`, 或者在已登录的浏览器中打开
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QrCode {
+    /// 二维码图片的地址
+    pub qr_code_url: String,
+    pub sid: String,
+}
+
+impl QrCode {
+    /// 二维码的内容
+    pub fn authorize_url(&self) -> String {
+        format!("https://www.alipan.com/o/oauth/authorize?sid={}", self.sid)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QrCodeStatus {
+    /// `WaitLogin` `ScanSuccess` `LoginSuccess` `QRCodeExpired`
+    pub status: String,
+    /// `LoginSuccess` 时返回, 用于换取token
+    #[serde(default)]
+    pub auth_code: Option<String>,
+}
+
+/// 获取扫码登录的二维码, 没有 client_secret 时使用 PKCE
+pub async fn qrcode_authorize(app: &AppConfig, scope: &str, pkce: &Pkce) -> anyhow::Result<QrCode> {
+    let mut body = json!({
+        "client_id": app.client_id,
+        "scopes": scope.split(',').map(str::trim).collect::<Vec<_>>(),
+    });
+    if app.client_secret.is_empty() {
+        body["code_challenge"] = pkce.challenge.clone().into();
+        body["code_challenge_method"] = "S256".into();
+    } else {
+        body["client_secret"] = app.client_secret.clone().into();
+    }
+    retry(|| async {
+        let resp = reqwest::Client::new()
+            .post(format!("{}/oauth/authorize/qrcode", API_HOST))
+            .json(&body)
+            .send()
+            .await?;
+        Ok::<_, anyhow::Error>(check_status(resp)?.json::<QrCode>().await?)
+    })
+    .await
+}
+
+pub async fn qrcode_status(sid: &str) -> anyhow::Result<QrCodeStatus> {
+    retry(|| async {
+        let resp = reqwest::get(format!("{}/oauth/qrcode/{}/status", API_HOST, sid)).await?;
+        Ok::<_, anyhow::Error>(check_status(resp)?.json::<QrCodeStatus>().await?)
+    })
+    .await
+}
+
+/// 请求 `/oauth/access_token`, client_secret 为空时不发送(PKCE)
+async fn request_access_token(
+    app: &AppConfig,
+    params: &[(&str, &str)],
+) -> anyhow::Result<AccessToken> {
+    let mut form = vec![("client_id", app.client_id.as_str())];
+    if !app.client_secret.is_empty() {
+        form.push(("client_secret", app.client_secret.as_str()));
+    }
+    form.extend_from_slice(params);
+    let resp = reqwest::Client::new()
+        .post(format!("{}/oauth/access_token", API_HOST))
+        .form(&form)
+        .send()
+        .await?;
+    let token = check_status(resp)?.json::<OauthAccessToken>().await?;
+    Ok(AccessToken::wrap_oauth_token(token))
+}
+
+/// 使用授权码换取token, 授权时使用了 PKCE 的需要 code_verifier
+///
+/// 授权码只能使用一次, 上一次请求可能已经在服务端生效, 所以不重试, 失败时需要重新授权
+pub async fn access_token_by_code(
+    app: &AppConfig,
+    code: &str,
+    code_verifier: Option<&str>,
+) -> anyhow::Result<AccessToken> {
+    let mut params = vec![("grant_type", "authorization_code"), ("code", code)];
+    if let Some(code_verifier) = code_verifier {
+        params.push(("code_verifier", code_verifier));
+    }
+    request_access_token(app, &params).await
+}
+
+pub async fn refresh_access_token(
+    app: &AppConfig,
+    refresh_token: &str,
+) -> anyhow::Result<AccessToken> {
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+    retry(|| request_access_token(app, &params)).await
+}
@@ -0,0 +1,172 @@
+use crate::events;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use once_cell::sync::OnceCell;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// `up` / `down` 的进度显示, 未初始化时(标准输出不是终端)所有函数都不做任何事
+struct Progress {
+    multi: MultiProgress,
+    total: ProgressBar,
+    total_files: u64,
+    done_files: AtomicU64,
+    /// 当前正在传输的文件, 以及开始传输时总进度的位置, 重试时回退到这个位置
+    current: Mutex<Option<(ProgressBar, u64)>>,
+}
+
+static PROGRESS_CELL: OnceCell<Progress> = OnceCell::new();
+
+/// 标准输出是终端, 并且没有输出事件时才显示进度
+pub fn enabled() -> bool {
+    std::io::stdout().is_terminal() && !events::enabled()
+}
+
+pub fn init(total_files: u64, total_bytes: u64) {
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    let total = multi.add(ProgressBar::new(total_bytes));
+    total.set_style(
+        ProgressStyle::with_template(
+            "{msg} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec} 剩余 {eta}",
+        )
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    total.set_message(format!("0/{}", total_files));
+    let _ = PROGRESS_CELL.set(Progress {
+        multi,
+        total,
+        total_files,
+        done_files: AtomicU64::new(0),
+        current: Mutex::new(None),
+    });
+}
+
+pub fn finish() {
+    if let Some(progress) = PROGRESS_CELL.get() {
+        progress.total.finish_and_clear();
+    }
+}
+
+/// 开始传输一个文件, 返回的 FileProgress 被释放时这个文件结束
+pub fn begin_file(name: &str, size: u64) -> FileProgress {
+    if let Some(progress) = PROGRESS_CELL.get() {
+        let bar = progress.multi.add(ProgressBar::new(size));
+        bar.set_style(
+            ProgressStyle::with_template("  {wide_msg} {percent:>3}% {bytes}/{total_bytes}")
+                .unwrap(),
+        );
+        bar.set_message(name.to_string());
+        *progress.current.lock().unwrap() = Some((bar, progress.total.position()));
+    }
+    FileProgress { size }
+}
+
+/// 重试时从头开始计算当前文件的进度
+pub fn restart_file() {
+    events::transfer_restart();
+    if let Some(progress) = PROGRESS_CELL.get() {
+        if let Some((bar, base)) = progress.current.lock().unwrap().as_ref() {
+            bar.set_position(0);
+            progress.total.set_position(*base);
+        }
+    }
+}
+
+/// 当前文件传输了 bytes 字节
+pub fn inc(bytes: u64) {
+    events::transfer_bytes(bytes);
+    if let Some(progress) = PROGRESS_CELL.get() {
+        if let Some((bar, _)) = progress.current.lock().unwrap().as_ref() {
+            bar.inc(bytes);
+        }
+        progress.total.inc(bytes);
+    }
+}
+
+/// 不需要传输的文件
+pub fn skip_file(size: u64) {
+    if let Some(progress) = PROGRESS_CELL.get() {
+        progress.total.inc(size);
+        progress.file_done();
+    }
+}
+
+impl Progress {
+    fn file_done(&self) {
+        let done = self.done_files.fetch_add(1, Ordering::Relaxed) + 1;
+        self.total
+            .set_message(format!("{}/{}", done, self.total_files));
+    }
+}
+
+pub struct FileProgress {
+    size: u64,
+}
+
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        if let Some(progress) = PROGRESS_CELL.get() {
+            // 失败或者秒传时也将总进度推进到这个文件结束的位置
+            if let Some((bar, base)) = progress.current.lock().unwrap().take() {
+                bar.finish_and_clear();
+                progress.multi.remove(&bar);
+                progress.total.set_position(base + self.size);
+            }
+            progress.file_done();
+        }
+    }
+}
+
+/// 日志输出到终端时先隐藏进度条, 避免日志和进度条混在一起,
+/// 警告和错误输出到标准错误, 输出事件时所有日志都输出到标准错误
+pub struct ConsoleWriter {
+    stderr: bool,
+}
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let write = || {
+            if self.stderr || events::enabled() {
+                std::io::stderr().write(buf)
+            } else {
+                std::io::stdout().write(buf)
+            }
+        };
+        match PROGRESS_CELL.get() {
+            Some(progress) => progress.multi.suspend(write),
+            None => write(),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.stderr || events::enabled() {
+            return std::io::stderr().flush();
+        }
+        std::io::stdout().flush()
+    }
+}
+
+/// 按日志级别选择 [`ConsoleWriter`] 的输出
+pub struct Console;
+
+impl<'a> MakeWriter<'a> for Console {
+    type Writer = ConsoleWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ConsoleWriter { stderr: false }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        ConsoleWriter {
+            stderr: *meta.level() <= Level::WARN,
+        }
+    }
+}
+
+/// 终端支持颜色时日志才使用颜色, 输出重定向到文件或者管道时不输出转义字符
+pub fn console_ansi() -> bool {
+    std::io::stdout().is_terminal() && std::io::stderr().is_terminal()
+}
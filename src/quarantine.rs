@@ -0,0 +1,144 @@
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use std::path::{Component, Path, PathBuf};
+
+pub const DEFAULT_QUARANTINE_DIR: &str = ".arsync/quarantine";
+pub const DEFAULT_RETENTION_DAYS: &str = "30";
+const BATCH_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// 本地隔离区, `down` 需要删除本地文件时, 将其移动到 `隔离区/{日期时间}/{相对路径}` 而不是直接删除
+pub struct Quarantine {
+    target_root: PathBuf,
+    root: PathBuf,
+    batch: PathBuf,
+}
+
+impl Quarantine {
+    /// root 为空时使用 `{target_root}/.arsync/quarantine`, 以`.`开头的文件夹不参与同步
+    ///
+    /// 指定的隔离区在目标路径中时, 必须位于以`.`开头的文件夹中, 否则会被同步删除, 移入隔离区时也会复制到自身中
+    pub fn new(target_root: &str, root: Option<&str>) -> anyhow::Result<Self> {
+        let target_root = PathBuf::from(target_root);
+        let root = match root {
+            Some(root) => {
+                let root = std::path::absolute(root)
+                    .with_context(|| format!("隔离区路径不正确: {}", root))?;
+                check_outside_target(&std::path::absolute(&target_root)?, &root)?;
+                root
+            }
+            None => target_root.join(DEFAULT_QUARANTINE_DIR),
+        };
+        let batch = root.join(chrono::Local::now().format(BATCH_FORMAT).to_string());
+        Ok(Quarantine {
+            target_root,
+            root,
+            batch,
+        })
+    }
+
+    /// 删除超过保留天数的隔离批次, 0 表示永久保留
+    pub async fn purge_expired(&self, retention_days: u64) -> anyhow::Result<()> {
+        if retention_days == 0 {
+            return Ok(());
+        }
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("读取隔离区失败: {:?}", self.root))
+            }
+        };
+        let deadline =
+            chrono::Local::now().naive_local() - chrono::Duration::days(retention_days as i64);
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let Ok(created_at) = NaiveDateTime::parse_from_str(name, BATCH_FORMAT) else {
+                continue;
+            };
+            if created_at < deadline {
+                tracing::info!("清理隔离区 : {:?}", entry.path());
+                tokio::fs::remove_dir_all(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 将目标路径中的文件或文件夹移入本次的隔离批次, 返回移动后的路径
+    pub async fn move_in(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let relative = path
+            .strip_prefix(&self.target_root)
+            .with_context(|| format!("文件不在目标路径中: {:?}", path))?;
+        let dest = self.batch.join(relative);
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("创建隔离区失败: {:?}", parent))?;
+        }
+        match tokio::fs::rename(path, &dest).await {
+            Ok(()) => {}
+            // 隔离区与目标路径不在同一个设备上时无法rename, 复制后再删除
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                copy_recursive(path.to_path_buf(), dest.clone()).await?;
+                if tokio::fs::metadata(path).await?.is_dir() {
+                    tokio::fs::remove_dir_all(path).await?;
+                } else {
+                    tokio::fs::remove_file(path).await?;
+                }
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("移动到隔离区失败: {:?}", path));
+            }
+        }
+        Ok(dest)
+    }
+}
+
+/// 隔离区不能是目标路径本身, 在目标路径中时第一层文件夹必须以`.`开头
+fn check_outside_target(target_root: &Path, root: &Path) -> anyhow::Result<()> {
+    let root = normalize(root);
+    let Ok(relative) = root.strip_prefix(normalize(target_root)) else {
+        return Ok(());
+    };
+    match relative.components().next() {
+        Some(Component::Normal(name)) if name.to_string_lossy().starts_with('.') => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "隔离区不能在目标路径中, 除非位于以`.`开头的文件夹中: {:?}",
+            root
+        )),
+    }
+}
+
+/// 去掉路径中的 `.` 和 `..`, 不访问文件系统
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[async_recursion::async_recursion]
+async fn copy_recursive(from: PathBuf, to: PathBuf) -> anyhow::Result<()> {
+    let metadata = tokio::fs::metadata(&from).await?;
+    if metadata.is_dir() {
+        tokio::fs::create_dir_all(&to).await?;
+        let mut entries = tokio::fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            copy_recursive(entry.path(), to.join(entry.file_name())).await?;
+        }
+    } else {
+        tokio::fs::copy(&from, &to)
+            .await
+            .with_context(|| format!("复制到隔离区失败: {:?}", from))?;
+    }
+    Ok(())
+}
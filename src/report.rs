@@ -0,0 +1,226 @@
+use crate::events;
+use anyhow::Context;
+use chrono::{DateTime, Local};
+use serde_derive::Serialize;
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// 部分文件同步失败时的退出码, 与参数错误(2)以及其他错误(1)区分
+pub const PARTIAL_FAILURE_EXIT_CODE: i32 = 3;
+
+/// 一次 `up` / `down` 的统计, 结束时打印汇总, 可以输出json报告供监控使用
+///
+/// 单个文件或文件夹的错误使用 `--keep-going` 时记录下来继续同步其他文件
+pub struct SyncReport {
+    command: &'static str,
+    keep_going: bool,
+    started_at: DateTime<Local>,
+    started: Instant,
+    transferred_files: AtomicU64,
+    transferred_bytes: AtomicU64,
+    deleted_files: AtomicU64,
+    skipped_files: AtomicU64,
+    errors: Mutex<Vec<(String, anyhow::Error)>>,
+}
+
+/// `--report` 输出的json
+#[derive(Serialize)]
+struct JsonReport {
+    command: &'static str,
+    /// `success`, `partial` (使用 `--keep-going` 时部分文件失败), `failed`
+    status: &'static str,
+    started_at: String,
+    finished_at: String,
+    duration_secs: f64,
+    uploaded_files: u64,
+    downloaded_files: u64,
+    deleted_files: u64,
+    skipped_files: u64,
+    bytes_transferred: u64,
+    bytes_per_sec: f64,
+    error: Option<String>,
+    errors: Vec<JsonError>,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    path: String,
+    error: String,
+}
+
+impl SyncReport {
+    pub fn new(command: &'static str, keep_going: bool) -> Self {
+        SyncReport {
+            command,
+            keep_going,
+            started_at: Local::now(),
+            started: Instant::now(),
+            transferred_files: AtomicU64::new(0),
+            transferred_bytes: AtomicU64::new(0),
+            deleted_files: AtomicU64::new(0),
+            skipped_files: AtomicU64::new(0),
+            errors: Mutex::new(vec![]),
+        }
+    }
+
+    /// 上传或下载了一个文件, 秒传时 bytes 为 0
+    pub fn add_transferred(&self, bytes: u64) {
+        self.transferred_files.fetch_add(1, Ordering::Relaxed);
+        self.transferred_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 删除或移入历史版本、隔离区
+    pub fn add_deleted(&self) {
+        self.deleted_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 已经同步, 或者因为 `--no-delete` 跳过
+    pub fn add_skipped(&self) {
+        self.skipped_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// keep_going 时记录错误并返回 `Ok(None)`, 否则直接返回错误
+    pub fn check<T>(
+        &self,
+        path: impl Display,
+        result: anyhow::Result<T>,
+    ) -> anyhow::Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if self.keep_going => {
+                tracing::warn!("同步失败, 继续同步其他文件 : {} : {:#}", path, err);
+                events::emit(
+                    "error",
+                    json!({ "path": path.to_string(), "error": format!("{:#}", err) }),
+                );
+                self.errors.lock().unwrap().push((path.to_string(), err));
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// 打印汇总和所有错误, 写入json报告, 有错误时返回 `PartialFailure`
+    pub async fn finish(
+        &self,
+        result: anyhow::Result<()>,
+        report_path: Option<&String>,
+    ) -> anyhow::Result<()> {
+        let duration = self.started.elapsed().as_secs_f64();
+        let files = self.transferred_files.load(Ordering::Relaxed);
+        let bytes = self.transferred_bytes.load(Ordering::Relaxed);
+        let deleted = self.deleted_files.load(Ordering::Relaxed);
+        let skipped = self.skipped_files.load(Ordering::Relaxed);
+        let bytes_per_sec = if duration > 0.0 {
+            bytes as f64 / duration
+        } else {
+            0.0
+        };
+        let errors = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, err)| JsonError {
+                path: path.clone(),
+                error: format!("{:#}", err),
+            })
+            .collect::<Vec<_>>();
+        let status = match (&result, errors.is_empty()) {
+            (Err(_), _) => "failed",
+            (Ok(_), false) => "partial",
+            (Ok(_), true) => "success",
+        };
+        let transferred = if self.command == "up" {
+            "上传"
+        } else {
+            "下载"
+        };
+        tracing::info!(
+            "{}{} 个文件, 删除 {} 个, 跳过 {} 个, 失败 {} 个, 传输 {}, 用时 {:.1}秒, 平均 {}/s",
+            transferred,
+            files,
+            deleted,
+            skipped,
+            errors.len(),
+            format_bytes(bytes),
+            duration,
+            format_bytes(bytes_per_sec as u64),
+        );
+        if !errors.is_empty() {
+            tracing::error!("以下 {} 个文件或文件夹同步失败:", errors.len());
+            for error in &errors {
+                tracing::error!("  {} : {}", error.path, error.error);
+            }
+        }
+        let (uploaded_files, downloaded_files) = if self.command == "up" {
+            (files, 0)
+        } else {
+            (0, files)
+        };
+        let report = JsonReport {
+            command: self.command,
+            status,
+            started_at: self.started_at.to_rfc3339(),
+            finished_at: Local::now().to_rfc3339(),
+            duration_secs: duration,
+            uploaded_files,
+            downloaded_files,
+            deleted_files: deleted,
+            skipped_files: skipped,
+            bytes_transferred: bytes,
+            bytes_per_sec,
+            error: result.as_ref().err().map(|err| format!("{:#}", err)),
+            errors,
+        };
+        if let Some(error) = &report.error {
+            events::emit("error", json!({ "path": null, "error": error }));
+        }
+        events::emit("summary", &report);
+        // 写入失败时同步的结果仍然优先返回, 但是不能当作成功, 否则读取报告的监控不知道报告没有更新
+        let written = match report_path {
+            Some(report_path) => write_report(report_path, &report).await,
+            None => Ok(()),
+        };
+        result?;
+        written?;
+        let errors = self.errors.lock().unwrap().len();
+        if errors > 0 {
+            return Err(PartialFailure(errors).into());
+        }
+        Ok(())
+    }
+}
+
+async fn write_report(report_path: &str, report: &JsonReport) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(report)?;
+    tokio::fs::write(report_path, json)
+        .await
+        .with_context(|| format!("写入报告失败 : {}", report_path))
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// 同步完成, 但是有部分文件失败
+#[derive(Debug)]
+pub struct PartialFailure(pub usize);
+
+impl Display for PartialFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} 个文件或文件夹同步失败", self.0)
+    }
+}
+
+impl std::error::Error for PartialFailure {}
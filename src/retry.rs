@@ -0,0 +1,246 @@
+use crate::config;
+use alipan::{AlipanError, ErrorInfo};
+use rand::Rng;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::time::Duration;
+
+/// 请求失败的分类, 决定是否重试
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// 网络错误、超时、服务端5xx, 可以重试
+    Retryable,
+    /// 请求过于频繁, 等待更久后重试
+    RateLimited,
+    /// 授权失效, 重试无意义
+    Auth,
+    /// 其他错误, 例如参数错误、文件不存在
+    Fatal,
+}
+
+/// 自行发起的HTTP请求(上传、下载)返回的错误状态, 携带服务端的 `Retry-After`
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: reqwest::StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl Display for HttpStatusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http status: {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// 与 `error_for_status` 相同, 但会保留 `Retry-After`
+pub fn check_status(response: reqwest::Response) -> anyhow::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    Err(HttpStatusError {
+        status,
+        retry_after,
+    }
+    .into())
+}
+
+pub fn classify(err: &anyhow::Error) -> ErrorKind {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<AlipanError>() {
+            return classify_alipan(err);
+        }
+        if let Some(err) = cause.downcast_ref::<HttpStatusError>() {
+            return classify_status(err.status);
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return classify_reqwest(err);
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            // 本地文件不存在或没有权限, 重试无意义
+            return match err.kind() {
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+                    ErrorKind::Fatal
+                }
+                _ => ErrorKind::Retryable,
+            };
+        }
+    }
+    ErrorKind::Fatal
+}
+
+fn classify_alipan(err: &AlipanError) -> ErrorKind {
+    match &err.inner {
+        ErrorInfo::ReqwestError(err) => classify_reqwest(err),
+        ErrorInfo::Io(_) => ErrorKind::Retryable,
+        ErrorInfo::ServerError(err) => match err.code.as_str() {
+            "TooManyRequests" | "QuotaExhausted.Drive" => ErrorKind::RateLimited,
+            "InternalError" | "ServiceUnavailable" | "ServerError" | "Timeout" => {
+                ErrorKind::Retryable
+            }
+            code if code.starts_with("AccessToken")
+                || code.contains("RefreshToken")
+                || code.starts_with("PermissionDenied")
+                || code.eq("Forbidden") =>
+            {
+                ErrorKind::Auth
+            }
+            _ => ErrorKind::Fatal,
+        },
+        // 服务端返回的内容不是json时, 格式为 `server error: code: 502, content: ...`
+        ErrorInfo::Msg(msg) => msg
+            .strip_prefix("server error: code: ")
+            .and_then(|v| v.split(',').next())
+            .and_then(|v| v.parse::<u16>().ok())
+            .and_then(|v| reqwest::StatusCode::from_u16(v).ok())
+            .map_or(ErrorKind::Fatal, classify_status),
+        // 刷新 access token 失败
+        ErrorInfo::Anyhow(err) => match classify(err) {
+            ErrorKind::Fatal => ErrorKind::Auth,
+            kind => kind,
+        },
+        _ => ErrorKind::Fatal,
+    }
+}
+
+fn classify_reqwest(err: &reqwest::Error) -> ErrorKind {
+    if let Some(status) = err.status() {
+        return classify_status(status);
+    }
+    if err.is_timeout() || err.is_connect() || err.is_request() || err.is_body() {
+        return ErrorKind::Retryable;
+    }
+    ErrorKind::Fatal
+}
+
+fn classify_status(status: reqwest::StatusCode) -> ErrorKind {
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        ErrorKind::RateLimited
+    } else if status == reqwest::StatusCode::UNAUTHORIZED {
+        ErrorKind::Auth
+    } else if status.is_server_error() || status == reqwest::StatusCode::REQUEST_TIMEOUT {
+        ErrorKind::Retryable
+    } else {
+        ErrorKind::Fatal
+    }
+}
+
+/// 云端文件或者上传不存在, 重试非幂等的请求时说明上一次请求已经生效
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<AlipanError>().is_some_and(|err| {
+            matches!(&err.inner, ErrorInfo::ServerError(err) if err.code.starts_with("NotFound"))
+        })
+    })
+}
+
+fn retry_after(err: &anyhow::Error) -> Option<Duration> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<HttpStatusError>())
+        .and_then(|err| err.retry_after)
+}
+
+/// 服务端拒绝了 access_token, 刷新token失败时的错误中已经有重新登录的提示
+fn is_token_rejected(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<AlipanError>())
+        .any(|err| !matches!(err.inner, ErrorInfo::Anyhow(_)))
+}
+
+/// 失败时按照配置的次数重试, 指数退避并加入随机抖动, 限流时优先使用 `Retry-After`
+///
+/// ```ignore
+/// let request = client.adrive_open_file_list().await.drive_id(drive_id);
+/// let list = retry(|| request.request()).await?;
+/// ```
+pub async fn retry<T, E, F, Fut>(mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<anyhow::Error>,
+{
+    let retry_config = config::get_retry_config().await;
+    let mut attempt = 0;
+    loop {
+        let err = match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err.into(),
+        };
+        let kind = classify(&err);
+        if kind == ErrorKind::Auth && is_token_rejected(&err) {
+            return Err(err.context(format!(
+                "授权失效或者权限不足, 请运行 {} 重新登录",
+                config::current_login_hint()
+            )));
+        }
+        if matches!(kind, ErrorKind::Auth | ErrorKind::Fatal) || attempt >= retry_config.max_retries
+        {
+            return Err(err);
+        }
+        let backoff = retry_config
+            .initial_backoff_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(retry_config.max_backoff_ms);
+        let backoff = rand::thread_rng().gen_range(backoff / 2..=backoff);
+        let delay = match (kind, retry_after(&err)) {
+            (_, Some(retry_after)) => retry_after,
+            (ErrorKind::RateLimited, None) => {
+                Duration::from_millis(backoff.max(retry_config.rate_limit_backoff_ms))
+            }
+            _ => Duration::from_millis(backoff),
+        };
+        attempt += 1;
+        tracing::warn!(
+            "请求失败, {:.1}秒后重试 ({}/{}) : {}",
+            delay.as_secs_f64(),
+            attempt,
+            retry_config.max_retries,
+            err
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// 为 alipan 的请求增加重试, 将 `.request().await` 替换为 `.retry_request().await`
+///
+/// 只用于可以重复发送的请求, 放入回收站、移动、完成上传等请求在 `common` 中检查上一次请求是否已经生效
+pub(crate) trait RetryRequest {
+    type Output;
+
+    async fn retry_request(&self) -> anyhow::Result<Self::Output>;
+}
+
+macro_rules! retry_request {
+    ($($request:ty => $output:ty,)*) => {
+        $(
+            impl RetryRequest for $request {
+                type Output = $output;
+
+                async fn retry_request(&self) -> anyhow::Result<$output> {
+                    retry(|| self.request()).await
+                }
+            }
+        )*
+    };
+}
+
+retry_request! {
+    alipan::AdriveOpenFileListRequest => alipan::response::AdriveOpenFileList,
+    alipan::adrive_open_file_get_download_url::AdriveOpenFileGetDownloadUrlRequest =>
+        alipan::adrive_open_file_get_download_url::AdriveOpenFileGetDownloadUrl,
+    alipan::AdriveOpenFileGetUploadUrlRequest => alipan::AdriveOpenFileGetUploadUrl,
+    // 创建文件夹(Refuse)时已存在则返回已有的文件夹, 可以重试, 创建文件使用 `create_remote_file`
+    alipan::AdriveOpenFileCreateRequest => alipan::AdriveOpenFileCreate,
+    alipan::AdriveOpenFileGetRequest => alipan::AdriveOpenFileGet,
+    alipan::AdriveOpenFileAsyncTaskGetRequest => alipan::AdriveOpenFileAsyncTaskGet,
+    alipan::adrive_open_file_get_by_path::AdriveOpenFileGetByPathRequest => alipan::AdriveOpenFileGet,
+    alipan::AdriveUserGetDriveInfoRequest => alipan::AdriveUserGetDriveInfo,
+    alipan::oauth_users_info::OauthUsersInfoRequest => alipan::oauth_users_info::OauthUsersInfo,
+}
@@ -0,0 +1,214 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::Context;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use clap::arg;
+use once_cell::sync::OnceCell;
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+/// 主密码的环境变量
+pub const PASSWORD_ENV: &str = "ARSYNC_CONFIG_PASSWORD";
+/// 加密后的字段的前缀
+const ENCRYPTED_PREFIX: &str = "enc:";
+/// 加密后保存在配置文件中, 用于检查主密码或者密钥文件是否正确
+const CHECK_PLAINTEXT: &str = "arsync";
+const PBKDF2_ROUNDS: u32 = 100_000;
+const NONCE_LEN: usize = 12;
+
+/// 配置文件中的 `[encryption]`, 存在时app密钥、token和任务的密码加密保存
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    pub mode: EncryptionMode,
+    /// 派生密钥使用的盐, hex
+    pub salt: String,
+    pub check: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptionMode {
+    /// 使用主密码, PBKDF2-HMAC-SHA256 派生密钥
+    Password,
+    /// 使用密钥文件的内容派生密钥
+    KeyFile,
+}
+
+enum MasterKey {
+    Password(String),
+    KeyFile(Vec<u8>),
+}
+
+impl MasterKey {
+    fn mode(&self) -> EncryptionMode {
+        match self {
+            MasterKey::Password(_) => EncryptionMode::Password,
+            MasterKey::KeyFile(_) => EncryptionMode::KeyFile,
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> [u8; 32] {
+        match self {
+            MasterKey::Password(password) => {
+                pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, PBKDF2_ROUNDS)
+            }
+            MasterKey::KeyFile(content) => {
+                let mut hasher = Sha256::new();
+                hasher.update(salt);
+                hasher.update(content);
+                hasher.finalize().into()
+            }
+        }
+    }
+}
+
+static MASTER_KEY_CELL: OnceCell<Option<MasterKey>> = OnceCell::new();
+/// 派生的密钥和使用的盐, 盐不变时不重新派生
+static CIPHER: Mutex<Option<(String, Aes256Gcm)>> = Mutex::new(None);
+
+pub fn args() -> Vec<clap::Arg> {
+    vec![
+        arg!(--"config-password-file" <FILE> "从文件读取配置文件的主密码, 也可以使用环境变量 `ARSYNC_CONFIG_PASSWORD`")
+            .required(false)
+            .conflicts_with("config-key-file"),
+        arg!(--"config-key-file" <FILE> "使用密钥文件加解密配置文件中的密钥")
+            .required(false),
+    ]
+}
+
+/// 根据命令行参数和环境变量设置主密码或者密钥文件, 需要在加载配置文件之前调用
+pub fn init(matches: &clap::ArgMatches) -> anyhow::Result<()> {
+    let master_key = if let Some(path) = matches.get_one::<String>("config-key-file") {
+        let content =
+            std::fs::read(path).with_context(|| format!("读取密钥文件失败 : {}", path))?;
+        if content.is_empty() {
+            return Err(anyhow::anyhow!("密钥文件为空 : {}", path));
+        }
+        Some(MasterKey::KeyFile(content))
+    } else if let Some(path) = matches.get_one::<String>("config-password-file") {
+        let password = std::fs::read_to_string(path)
+            .with_context(|| format!("读取密码文件失败 : {}", path))?;
+        Some(MasterKey::Password(password.trim().to_string()))
+    } else {
+        std::env::var(PASSWORD_ENV).ok().map(MasterKey::Password)
+    };
+    if let Some(MasterKey::Password(password)) = &master_key {
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("配置文件的主密码不能为空"));
+        }
+    }
+    MASTER_KEY_CELL
+        .set(master_key)
+        .map_err(|_| anyhow::anyhow!("主密码重复设置"))
+}
+
+fn master_key(mode: EncryptionMode) -> anyhow::Result<&'static MasterKey> {
+    let master_key = MASTER_KEY_CELL.get().and_then(Option::as_ref);
+    match master_key {
+        Some(master_key) if master_key.mode() == mode => Ok(master_key),
+        _ => Err(match mode {
+            EncryptionMode::Password => anyhow::anyhow!(
+                "配置文件使用主密码加密, 请使用 `--config-password-file` 或者环境变量 `{}` 提供主密码",
+                PASSWORD_ENV
+            ),
+            EncryptionMode::KeyFile => {
+                anyhow::anyhow!("配置文件使用密钥文件加密, 请使用 `--config-key-file` 提供密钥文件")
+            }
+        }),
+    }
+}
+
+fn set_cipher(salt: &str, key: [u8; 32]) {
+    *CIPHER.lock().unwrap() = Some((salt.to_string(), Aes256Gcm::new(&key.into())));
+}
+
+fn cipher() -> anyhow::Result<Aes256Gcm> {
+    CIPHER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|(_, cipher)| cipher.clone())
+        .ok_or_else(|| anyhow::anyhow!("配置文件的密钥未设置"))
+}
+
+/// 加载加密的配置文件时, 派生密钥并检查主密码或者密钥文件是否正确
+///
+/// 重新读取配置文件时盐没有变化(没有重新加密)则使用已经派生的密钥
+pub fn unlock(encryption: &EncryptionConfig) -> anyhow::Result<()> {
+    let derived = CIPHER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|(salt, _)| *salt == encryption.salt);
+    if !derived {
+        let master_key = master_key(encryption.mode)?;
+        let salt = hex::decode(&encryption.salt).with_context(|| "encryption.salt 格式不正确")?;
+        set_cipher(&encryption.salt, master_key.derive(&salt));
+    }
+    match decrypt(&encryption.check) {
+        Ok(check) if check == CHECK_PLAINTEXT => Ok(()),
+        _ => Err(anyhow::anyhow!("配置文件的主密码或者密钥文件不正确")),
+    }
+}
+
+/// 开始加密配置文件, 使用命令行参数或者环境变量提供的主密码或者密钥文件
+pub fn new_encryption() -> anyhow::Result<EncryptionConfig> {
+    let master_key = MASTER_KEY_CELL
+        .get()
+        .and_then(Option::as_ref)
+        .with_context(|| {
+            format!(
+                "请使用 `--config-password-file`、环境变量 `{}` 或者 `--config-key-file` 提供主密码或者密钥文件",
+                PASSWORD_ENV
+            )
+        })?;
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt_hex = hex::encode(salt);
+    set_cipher(&salt_hex, master_key.derive(&salt));
+    Ok(EncryptionConfig {
+        mode: master_key.mode(),
+        salt: salt_hex,
+        check: encrypt(CHECK_PLAINTEXT)?,
+    })
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// 加密为 `enc:` + base64(nonce + 密文), 每次使用随机的 nonce
+pub fn encrypt(value: &str) -> anyhow::Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut buff = nonce.to_vec();
+    buff.extend(
+        cipher()?
+            .encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+            .map_err(|e| anyhow::anyhow!("加密时出错: {}", e))?,
+    );
+    Ok(format!(
+        "{}{}",
+        ENCRYPTED_PREFIX,
+        BASE64_STANDARD.encode(buff)
+    ))
+}
+
+pub fn decrypt(value: &str) -> anyhow::Result<String> {
+    let buff = value
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .map(|value| BASE64_STANDARD.decode(value))
+        .with_context(|| "不是加密的值")??;
+    if buff.len() < NONCE_LEN {
+        return Err(anyhow::anyhow!("加密的值格式不正确"));
+    }
+    let (nonce, ciphertext) = buff.split_at(NONCE_LEN);
+    let plain = cipher()?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| anyhow::anyhow!("解密时出错: {}", e))?;
+    Ok(String::from_utf8(plain)?)
+}
@@ -0,0 +1,535 @@
+use crate::common::{
+    create_remote_folder, find_remote_file, list_remote_folder_file, move_remote_file,
+};
+use crate::custom_crypto::decrypt_file_name;
+use alipan::response::AdriveOpenFile;
+use alipan::{AdriveClient, AdriveOpenFileType};
+use anyhow::Context;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const VERSIONS_FOLDER: [&str; 2] = [".arsync", "versions"];
+const SNAPSHOT_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// 云端的历史版本区 `{目标文件夹}/.arsync/versions/{时间}/{相对路径}`,
+/// `up` 时被替换或删除的云端文件移动到这里, 而不是放入回收站
+pub struct RemoteVersions {
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    root_folder_id: String,
+    time: DateTime<Utc>,
+    folder_ids: Mutex<HashMap<Vec<String>, String>>,
+}
+
+impl RemoteVersions {
+    pub fn new(client: Arc<AdriveClient>, drive_id: String, root_folder_id: String) -> Self {
+        Self::with_time(client, drive_id, root_folder_id, Utc::now())
+    }
+
+    fn with_time(
+        client: Arc<AdriveClient>,
+        drive_id: String,
+        root_folder_id: String,
+        time: DateTime<Utc>,
+    ) -> Self {
+        RemoteVersions {
+            client,
+            drive_id,
+            root_folder_id,
+            time: time.with_nanosecond(0).unwrap_or(time),
+            folder_ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 新的一次快照, 用于持续同步时的每一次同步, 快照的名称精确到秒, 时间至少比上一次晚一秒
+    pub fn next_snapshot(&self) -> Self {
+        Self::with_time(
+            Arc::clone(&self.client),
+            self.drive_id.clone(),
+            self.root_folder_id.clone(),
+            Utc::now().max(self.time + chrono::Duration::seconds(1)),
+        )
+    }
+
+    /// 将云端文件移动到本次快照中, relative_path 是文件所在文件夹相对于目标文件夹的路径(云端名称)
+    pub async fn move_in(
+        &self,
+        relative_path: &[String],
+        file: &AdriveOpenFile,
+    ) -> anyhow::Result<()> {
+        let parent_id = self.ensure_folder(relative_path).await?;
+        move_remote_file(
+            Arc::clone(&self.client),
+            self.drive_id.clone(),
+            file.file_id.clone(),
+            parent_id,
+        )
+        .await
+    }
+
+    async fn ensure_folder(&self, relative_path: &[String]) -> anyhow::Result<String> {
+        let mut folder_ids = self.folder_ids.lock().await;
+        let mut path = vec![];
+        let mut folder_id = self.root_folder_id.clone();
+        let names = VERSIONS_FOLDER
+            .iter()
+            .map(|x| x.to_string())
+            .chain(std::iter::once(
+                self.time.format(SNAPSHOT_FORMAT).to_string(),
+            ))
+            .chain(relative_path.iter().cloned());
+        for name in names {
+            path.push(name.clone());
+            folder_id = if let Some(id) = folder_ids.get(&path) {
+                id.clone()
+            } else {
+                let id =
+                    create_remote_folder(&self.client, self.drive_id.clone(), folder_id, &name)
+                        .await?;
+                folder_ids.insert(path.clone(), id.clone());
+                id
+            };
+        }
+        Ok(folder_id)
+    }
+}
+
+/// 云端文件的一个版本, 从 valid_from 开始生效, 在 valid_until 被替换或删除, 当前版本的 valid_until 为空
+pub struct FileVersion {
+    pub path: String,
+    pub file: AdriveOpenFile,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+/// 历史版本区中的一次快照
+pub struct Snapshot {
+    pub time: DateTime<Utc>,
+    pub folder: AdriveOpenFile,
+}
+
+pub async fn list_snapshots(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    root_folder_id: String,
+) -> anyhow::Result<Vec<Snapshot>> {
+    let mut folder_id = root_folder_id;
+    for name in VERSIONS_FOLDER {
+        match find_remote_file(client, drive_id.clone(), folder_id, name).await? {
+            Some(folder) => folder_id = folder.file_id,
+            None => return Ok(vec![]),
+        }
+    }
+    let mut snapshots = vec![];
+    for folder in list_remote_folder_file(client, drive_id, folder_id).await? {
+        if let Ok(time) = NaiveDateTime::parse_from_str(&folder.name, SNAPSHOT_FORMAT) {
+            snapshots.push(Snapshot {
+                time: time.and_utc(),
+                folder,
+            });
+        }
+    }
+    snapshots.sort_by_key(|x| x.time);
+    Ok(snapshots)
+}
+
+/// 递归列出云端文件夹中的文件, 返回解密后的相对路径, 无法解密的文件会被忽略
+#[async_recursion::async_recursion]
+pub async fn walk_remote_files(
+    client: Arc<AdriveClient>,
+    drive_id: String,
+    folder_id: String,
+    sync_password: Option<Vec<u8>>,
+    prefix: String,
+) -> anyhow::Result<Vec<(String, AdriveOpenFile)>> {
+    let mut files = vec![];
+    for x in list_remote_folder_file(&client, drive_id.clone(), folder_id).await? {
+        let name = match &sync_password {
+            Some(sync_password) => match decrypt_file_name(&x.name, sync_password) {
+                Ok(name) => name,
+                Err(_) => continue,
+            },
+            None => x.name.clone(),
+        };
+        let path = format!("{}{}", prefix, name);
+        match x.r#type {
+            AdriveOpenFileType::File => files.push((path, x)),
+            AdriveOpenFileType::Folder => {
+                files.extend(
+                    walk_remote_files(
+                        Arc::clone(&client),
+                        drive_id.clone(),
+                        x.file_id.clone(),
+                        sync_password.clone(),
+                        format!("{}/", path),
+                    )
+                    .await?,
+                );
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// 列出目标文件夹中所有文件的当前版本以及历史版本
+pub async fn list_file_versions(
+    client: &Arc<AdriveClient>,
+    drive_id: String,
+    root_folder_id: String,
+    sync_password: Option<Vec<u8>>,
+) -> anyhow::Result<Vec<FileVersion>> {
+    let mut versions = vec![];
+    let current = walk_remote_files(
+        Arc::clone(client),
+        drive_id.clone(),
+        root_folder_id.clone(),
+        sync_password.clone(),
+        "".to_string(),
+    )
+    .await?;
+    for (path, file) in current {
+        versions.push(FileVersion {
+            path,
+            valid_from: file.created_at,
+            valid_until: None,
+            file,
+        });
+    }
+    for snapshot in list_snapshots(client, drive_id.clone(), root_folder_id).await? {
+        let files = walk_remote_files(
+            Arc::clone(client),
+            drive_id.clone(),
+            snapshot.folder.file_id.clone(),
+            sync_password.clone(),
+            "".to_string(),
+        )
+        .await?;
+        for (path, file) in files {
+            versions.push(FileVersion {
+                path,
+                valid_from: file.created_at,
+                valid_until: Some(snapshot.time),
+                file,
+            });
+        }
+    }
+    Ok(versions)
+}
+
+/// 选出在某一时刻生效的文件版本
+///
+/// 被替换的版本的 valid_until 是那一次同步开始的时间, 而新版本的 valid_from 是上传完成的时间,
+/// 时刻落在两者之间时使用被替换的版本。新版本在之后的同步中才上传(中间还有其他快照)时, 文件在这期间是被删除的
+pub fn versions_at(versions: &[FileVersion], at: DateTime<Utc>) -> Vec<&FileVersion> {
+    let snapshot_times = versions
+        .iter()
+        .filter_map(|x| x.valid_until)
+        .collect::<HashSet<_>>();
+    let mut by_path = HashMap::<&str, Vec<&FileVersion>>::new();
+    for version in versions {
+        by_path
+            .entry(version.path.as_str())
+            .or_default()
+            .push(version);
+    }
+    let mut selected = vec![];
+    for versions in by_path.into_values() {
+        let covering = versions
+            .iter()
+            .filter(|x| x.valid_from <= at && x.valid_until.is_none_or(|until| until > at))
+            .max_by_key(|x| x.valid_from);
+        if let Some(version) = covering {
+            selected.push(*version);
+            continue;
+        }
+        let superseded = versions
+            .iter()
+            .filter_map(|x| {
+                x.valid_until
+                    .filter(|until| *until <= at)
+                    .map(|until| (x, until))
+            })
+            .max_by_key(|(x, until)| (*until, x.valid_from));
+        let replacement = versions
+            .iter()
+            .filter(|x| x.valid_from > at)
+            .min_by_key(|x| x.valid_from);
+        if let (Some((version, until)), Some(replacement)) = (superseded, replacement) {
+            let same_sync = !snapshot_times
+                .iter()
+                .any(|time| *time > until && *time <= replacement.valid_from);
+            if same_sync {
+                selected.push(*version);
+            }
+        }
+    }
+    selected.sort_by(|a, b| a.path.cmp(&b.path));
+    selected
+}
+
+/// 历史版本的保留策略, 任意一条策略保留的版本都会被保留
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+    }
+
+    /// 返回每个时间是否需要保留
+    pub fn keep(&self, times: &[DateTime<Utc>]) -> Vec<bool> {
+        let mut order = (0..times.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(times[*i]));
+        let mut keep = vec![false; times.len()];
+        for i in order.iter().take(self.keep_last) {
+            keep[*i] = true;
+        }
+        let buckets: [(usize, &str); 3] = [
+            (self.keep_daily, "%Y-%m-%d"),
+            (self.keep_weekly, "%G-W%V"),
+            (self.keep_monthly, "%Y-%m"),
+        ];
+        for (count, format) in buckets {
+            // 每个周期保留最新的一个, 共保留最近的count个周期
+            let mut seen = HashSet::new();
+            for i in &order {
+                if seen.len() >= count {
+                    break;
+                }
+                let bucket = times[*i].with_timezone(&Local).format(format).to_string();
+                if seen.insert(bucket) {
+                    keep[*i] = true;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// 解析本地时间 `2024-07-01 12:00:00`, `2024-07-01`, 或者 RFC3339 格式的时间
+pub fn parse_time(value: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(23, 59, 59).unwrap())
+        })
+        .with_context(|| format!("时间格式不正确: {}", value))?;
+    Ok(Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .with_context(|| format!("时间不存在: {}", value))?
+        .with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 本地时间, 按本地时间分组的结果与运行测试的时区无关
+    fn local(month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(2024, month, day, hour, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn policy(last: usize, daily: usize, weekly: usize, monthly: usize) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last: last,
+            keep_daily: daily,
+            keep_weekly: weekly,
+            keep_monthly: monthly,
+        }
+    }
+
+    fn utc(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 7, 1, hour, minute, 0).unwrap()
+    }
+
+    fn version(
+        path: &str,
+        valid_from: DateTime<Utc>,
+        valid_until: Option<DateTime<Utc>>,
+    ) -> FileVersion {
+        FileVersion {
+            path: path.to_string(),
+            file: AdriveOpenFile {
+                drive_id: "drive".to_string(),
+                file_id: format!("{}@{}", path, valid_from.timestamp()),
+                parent_file_id: "root".to_string(),
+                name: path.to_string(),
+                size: 0,
+                file_extension: "".to_string(),
+                content_hash: "".to_string(),
+                category: "".to_string(),
+                r#type: AdriveOpenFileType::File,
+                thumbnail: None,
+                url: None,
+                created_at: valid_from,
+                updated_at: valid_from,
+                play_cursor: None,
+                video_media_metadata: None,
+                video_preview_metadata: None,
+            },
+            valid_from,
+            valid_until,
+        }
+    }
+
+    /// 选中的版本的 (路径, valid_from)
+    fn selected_at(versions: &[FileVersion], at: DateTime<Utc>) -> Vec<(&str, DateTime<Utc>)> {
+        versions_at(versions, at)
+            .into_iter()
+            .map(|x| (x.path.as_str(), x.valid_from))
+            .collect()
+    }
+
+    #[test]
+    fn versions_at_current_file() {
+        let versions = [version("a", utc(1, 0), None)];
+        assert_eq!(selected_at(&versions, utc(5, 0)), vec![("a", utc(1, 0))]);
+        assert!(selected_at(&versions, utc(0, 30)).is_empty());
+    }
+
+    #[test]
+    fn versions_at_deleted_file() {
+        // 10:00 开始的同步中删除
+        let versions = [version("a", utc(1, 0), Some(utc(10, 0)))];
+        assert_eq!(selected_at(&versions, utc(9, 0)), vec![("a", utc(1, 0))]);
+        assert!(selected_at(&versions, utc(11, 0)).is_empty());
+    }
+
+    #[test]
+    fn versions_at_before_reupload_selects_replaced_version() {
+        // 10:00 开始的同步中替换, 新版本 10:05 上传完成
+        let versions = [
+            version("a", utc(1, 0), Some(utc(10, 0))),
+            version("a", utc(10, 5), None),
+        ];
+        assert_eq!(selected_at(&versions, utc(9, 0)), vec![("a", utc(1, 0))]);
+        assert_eq!(selected_at(&versions, utc(10, 2)), vec![("a", utc(1, 0))]);
+        assert_eq!(selected_at(&versions, utc(11, 0)), vec![("a", utc(10, 5))]);
+    }
+
+    #[test]
+    fn versions_at_reupload_in_later_sync() {
+        // 10:00 的同步中删除, 12:00 还有一次同步, 14:00 的同步中重新上传
+        let versions = [
+            version("a", utc(1, 0), Some(utc(10, 0))),
+            version("b", utc(1, 0), Some(utc(12, 0))),
+            version("a", utc(14, 5), None),
+        ];
+        assert_eq!(selected_at(&versions, utc(11, 0)), vec![("b", utc(1, 0))]);
+        assert!(selected_at(&versions, utc(13, 0)).is_empty());
+        assert_eq!(selected_at(&versions, utc(15, 0)), vec![("a", utc(14, 5))]);
+    }
+
+    #[test]
+    fn keep_last() {
+        let times = [
+            local(7, 2, 10),
+            local(7, 4, 10),
+            local(7, 1, 10),
+            local(7, 3, 10),
+        ];
+        assert_eq!(
+            policy(2, 0, 0, 0).keep(&times),
+            vec![false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn keep_daily() {
+        let times = [
+            local(7, 1, 10),
+            local(7, 1, 12),
+            local(7, 2, 9),
+            local(7, 3, 9),
+            local(7, 3, 8),
+        ];
+        assert_eq!(
+            policy(0, 2, 0, 0).keep(&times),
+            vec![false, false, true, true, false]
+        );
+    }
+
+    #[test]
+    fn keep_weekly() {
+        // 2024-07-01 是周一, 07-14 是周日, 与 07-08 在同一周
+        let times = [
+            local(7, 1, 10),
+            local(7, 3, 10),
+            local(7, 8, 10),
+            local(7, 14, 10),
+        ];
+        assert_eq!(
+            policy(0, 0, 2, 0).keep(&times),
+            vec![false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn keep_monthly() {
+        let times = [
+            local(6, 10, 10),
+            local(6, 20, 10),
+            local(7, 5, 10),
+            local(8, 1, 10),
+        ];
+        assert_eq!(
+            policy(0, 0, 0, 2).keep(&times),
+            vec![false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn keep_union_of_policies() {
+        let times = [
+            local(6, 10, 10),
+            local(6, 20, 10),
+            local(7, 5, 10),
+            local(7, 6, 10),
+        ];
+        assert_eq!(
+            policy(1, 0, 0, 2).keep(&times),
+            vec![false, true, false, true]
+        );
+        assert!(policy(0, 0, 0, 0).is_empty());
+        assert_eq!(policy(0, 0, 0, 0).keep(&times), vec![false; 4]);
+    }
+
+    #[test]
+    fn parse_time_formats() {
+        assert_eq!(
+            parse_time("2024-07-01T12:00:00Z").unwrap(),
+            Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time("2024-07-01T12:00:00+08:00").unwrap(),
+            Utc.with_ymd_and_hms(2024, 7, 1, 4, 0, 0).unwrap()
+        );
+        assert_eq!(parse_time("2024-07-01 12:00:00").unwrap(), local(7, 1, 12));
+        assert_eq!(parse_time("2024-07-01 12:00").unwrap(), local(7, 1, 12));
+        // 只有日期时为这一天的最后一秒
+        assert_eq!(
+            parse_time("2024-07-01").unwrap(),
+            local(7, 1, 23) + chrono::Duration::seconds(59 * 60 + 59)
+        );
+        assert!(parse_time("2024/07/01").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
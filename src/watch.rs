@@ -0,0 +1,123 @@
+use crate::common::is_ignore_file;
+use anyhow::Context;
+use futures_util::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 本地文件夹的变化
+pub enum WatchChanges {
+    /// 发生变化的文件夹(文件夹中的文件或子文件夹被创建、修改、删除、移动)
+    Folders(BTreeSet<PathBuf>),
+    /// 事件队列溢出, 有事件丢失, 需要完整同步
+    Overflow,
+}
+
+/// 使用inotify递归监听本地文件夹
+pub struct LocalWatcher {
+    root: PathBuf,
+    stream: EventStream<Vec<u8>>,
+    folders: HashMap<WatchDescriptor, PathBuf>,
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::MODIFY
+        | WatchMask::ATTRIB
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::ONLYDIR
+}
+
+impl LocalWatcher {
+    pub fn new(root: &str) -> anyhow::Result<Self> {
+        let inotify = Inotify::init().with_context(|| "初始化inotify失败")?;
+        let stream = inotify.into_event_stream(vec![0u8; 64 << 10])?;
+        let mut watcher = LocalWatcher {
+            root: PathBuf::from(root),
+            stream,
+            folders: HashMap::new(),
+        };
+        watcher.rewatch()?;
+        Ok(watcher)
+    }
+
+    /// 重新监听所有文件夹, 事件丢失后新建的文件夹可能没有被监听
+    pub fn rewatch(&mut self) -> anyhow::Result<()> {
+        let root = self.root.clone();
+        self.watch_recursive(&root)
+    }
+
+    fn watch_recursive(&mut self, folder: &Path) -> anyhow::Result<()> {
+        let wd = match self.stream.watches().add(folder, watch_mask()) {
+            Ok(wd) => wd,
+            // 文件夹已经被删除或移走
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "监听文件夹失败 : {:?}, 文件夹过多时请增加 fs.inotify.max_user_watches",
+                        folder
+                    )
+                })
+            }
+        };
+        self.folders.insert(wd, folder.to_path_buf());
+        let entries = match std::fs::read_dir(folder) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("读取文件夹失败 : {:?}", folder)),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let ignored = entry.file_name().to_str().is_none_or(is_ignore_file);
+            if !ignored && entry.file_type()?.is_dir() {
+                self.watch_recursive(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 等待本地文件夹发生变化, 直到 debounce 时间内没有新的事件
+    pub async fn next_changes(&mut self, debounce: Duration) -> anyhow::Result<WatchChanges> {
+        let mut folders = BTreeSet::new();
+        loop {
+            let event = if folders.is_empty() {
+                self.stream.next().await
+            } else {
+                match tokio::time::timeout(debounce, self.stream.next()).await {
+                    Ok(event) => event,
+                    Err(_) => return Ok(WatchChanges::Folders(folders)),
+                }
+            };
+            let event = event.with_context(|| "inotify已关闭")??;
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                return Ok(WatchChanges::Overflow);
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                self.folders.remove(&event.wd);
+                continue;
+            }
+            let Some(folder) = self.folders.get(&event.wd).cloned() else {
+                continue;
+            };
+            let Some(name) = event.name.as_ref().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if is_ignore_file(name) {
+                continue;
+            }
+            if event.mask.contains(EventMask::ISDIR)
+                && event
+                    .mask
+                    .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+            {
+                self.watch_recursive(&folder.join(name))?;
+            }
+            folders.insert(folder);
+        }
+    }
+}